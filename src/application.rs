@@ -2,6 +2,7 @@
 use std::env;
 use std::fs::File;
 use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
 
 use abscissa_core::{
     application::{self, AppCell},
@@ -11,7 +12,10 @@ use abscissa_core::{
 };
 
 use anyhow::Result;
-use simplelog::{CombinedLogger, LevelFilter, TermLogger, TerminalMode, WriteLogger};
+use log::{Log, Metadata, Record};
+use simplelog::{
+    CombinedLogger, Config, LevelFilter, SharedLogger, TermLogger, TerminalMode, WriteLogger,
+};
 
 // use crate::helpers::*;
 use crate::{commands::EntryPoint, config::RusticConfig};
@@ -105,14 +109,17 @@ impl Application for RusticApp {
             None => LevelFilter::Info,
         };
         match &config.global.log_file {
-            None => TermLogger::init(
-                level_filter,
-                simplelog::ConfigBuilder::new()
-                    .set_time_level(LevelFilter::Off)
-                    .build(),
-                TerminalMode::Stderr,
-                ColorChoice::Auto,
-            )
+            None => CombinedLogger::init(vec![
+                TermLogger::new(
+                    level_filter,
+                    simplelog::ConfigBuilder::new()
+                        .set_time_level(LevelFilter::Off)
+                        .build(),
+                    TerminalMode::Stderr,
+                    ColorChoice::Auto,
+                ),
+                Box::new(ErrorCollector),
+            ])
             .map_err(|e| FrameworkErrorKind::ConfigError.context(e))?,
 
             Some(file) => CombinedLogger::init(vec![
@@ -129,6 +136,7 @@ impl Application for RusticApp {
                     simplelog::Config::default(),
                     File::options().create(true).append(true).open(file)?,
                 ),
+                Box::new(ErrorCollector),
             ])
             .map_err(|e| FrameworkErrorKind::ConfigError.context(e))?,
         }
@@ -138,3 +146,100 @@ impl Application for RusticApp {
         Ok(())
     }
 }
+
+/// Warnings and errors collected while an [`ErrorCollection`] is active
+static COLLECTED_ERRORS: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+/// Log target of the archiver of `rustic_core`
+const ARCHIVER_TARGET: &str = "rustic_core::archiver";
+
+/// Start of the messages of the archiver about entries which are skipped due to an error
+const SKIPPED_ENTRY_MESSAGE: &str = "ignoring error";
+
+/// Logger which collects the errors due to which the archiver of `rustic_core` skipped an entry
+///
+/// `rustic_core` reports errors which don't abort a backup, e.g. unreadable files, only as log
+/// messages. These are collected while an [`ErrorCollection`] is active to be able to report them
+/// after the backup. Other warnings, e.g. about the parent snapshot, are not collected as they
+/// don't make the snapshot incomplete.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ErrorCollector;
+
+impl ErrorCollector {
+    /// Start collecting errors
+    ///
+    /// Errors are collected from all threads until the returned [`ErrorCollection`] is finished
+    /// or dropped. Only one collection may be active at a time.
+    pub fn start() -> ErrorCollection {
+        let previous = COLLECTED_ERRORS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .replace(Vec::new());
+        assert!(previous.is_none(), "error collection is already active!");
+        ErrorCollection(())
+    }
+}
+
+/// An active collection of errors, see [`ErrorCollector::start`]
+///
+/// When dropped, the collection stops and the collected errors are discarded.
+#[derive(Debug)]
+pub(crate) struct ErrorCollection(());
+
+impl ErrorCollection {
+    /// Stop collecting and get the collected errors
+    pub fn finish(self) -> Vec<String> {
+        COLLECTED_ERRORS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for ErrorCollection {
+    fn drop(&mut self) {
+        _ = COLLECTED_ERRORS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+    }
+}
+
+impl Log for ErrorCollector {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= LevelFilter::Warn && metadata.target() == ARCHIVER_TARGET
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = record.args().to_string();
+        if message.starts_with(SKIPPED_ENTRY_MESSAGE) {
+            if let Some(errors) = COLLECTED_ERRORS
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .as_mut()
+            {
+                errors.push(message.trim_end().to_string());
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+impl SharedLogger for ErrorCollector {
+    fn level(&self) -> LevelFilter {
+        LevelFilter::Warn
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
@@ -6,6 +6,7 @@ pub(crate) mod check;
 pub(crate) mod completions;
 pub(crate) mod config;
 pub(crate) mod copy;
+pub(crate) mod daemon;
 pub(crate) mod diff;
 pub(crate) mod dump;
 pub(crate) mod forget;
@@ -29,10 +30,11 @@ use std::sync::Arc;
 use crate::{
     commands::{
         backup::BackupCmd, cat::CatCmd, check::CheckCmd, completions::CompletionsCmd,
-        config::ConfigCmd, copy::CopyCmd, diff::DiffCmd, dump::DumpCmd, forget::ForgetCmd,
-        init::InitCmd, key::KeyCmd, list::ListCmd, ls::LsCmd, merge::MergeCmd, prune::PruneCmd,
-        repair::RepairCmd, repoinfo::RepoInfoCmd, restore::RestoreCmd, self_update::SelfUpdateCmd,
-        show_config::ShowConfigCmd, snapshots::SnapshotCmd, tag::TagCmd,
+        config::ConfigCmd, copy::CopyCmd, daemon::DaemonCmd, diff::DiffCmd, dump::DumpCmd,
+        forget::ForgetCmd, init::InitCmd, key::KeyCmd, list::ListCmd, ls::LsCmd, merge::MergeCmd,
+        prune::PruneCmd, repair::RepairCmd, repoinfo::RepoInfoCmd, restore::RestoreCmd,
+        self_update::SelfUpdateCmd, show_config::ShowConfigCmd, snapshots::SnapshotCmd,
+        tag::TagCmd,
     },
     config::{progress_options::ProgressOptions, RusticConfig},
     {Application, RUSTIC_APP},
@@ -45,6 +47,8 @@ use rustic_core::{OpenStatus, Repository};
 
 pub(super) mod constants {
     pub(super) const MAX_PASSWORD_RETRIES: usize = 5;
+    /// Exit code if a snapshot has been saved, but errors occurred which made it incomplete
+    pub(super) const EXIT_CODE_INCOMPLETE: i32 = 3;
 }
 
 /// Rustic Subcommands
@@ -69,6 +73,9 @@ enum RusticCmd {
     /// Copy snapshots to other repositories. Note: The target repositories must be given in the config file!
     Copy(CopyCmd),
 
+    /// Regularly run the backup jobs defined in the config file
+    Daemon(DaemonCmd),
+
     /// Compare two snapshots/paths
     /// Note that the exclude options only apply for comparison with a local path
     Diff(DiffCmd),
@@ -164,6 +171,7 @@ impl Configurable<RusticConfig> for EntryPoint {
 
         match &self.commands {
             RusticCmd::Forget(cmd) => cmd.override_config(config),
+            RusticCmd::Daemon(cmd) => cmd.override_config(config),
 
             // subcommands that don't need special overrides use a catch all
             _ => Ok(config),
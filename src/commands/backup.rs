@@ -1,22 +1,47 @@
 //! `backup` subcommand
 
-use std::path::PathBuf;
+use std::{
+    fmt::{self, Display},
+    io::Write,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+};
+#[cfg(not(windows))]
+use std::{
+    os::fd::{AsRawFd, RawFd},
+    process::{Child, Stdio},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
 
 use crate::{
-    commands::open_repository,
+    application::{ErrorCollection, ErrorCollector},
+    commands::{
+        constants::EXIT_CODE_INCOMPLETE,
+        diff::{diff, DiffType},
+        open_repository,
+    },
+    config::{hooks::Hooks, RusticConfig},
     helpers::bytes_size_to_string,
-    {status_err, Application, RUSTIC_APP},
+    {status_err, status_warn, Application, RUSTIC_APP},
 };
 use abscissa_core::{Command, Runnable, Shutdown};
 use anyhow::{bail, Context, Result};
-use log::{debug, info, warn};
+use chrono::{DateTime, Local};
+use log::{debug, error, info, warn};
 
 use merge::Merge;
-use serde::Deserialize;
+#[cfg(not(windows))]
+use nix::{
+    libc::STDIN_FILENO,
+    unistd::{close, dup, dup2},
+};
+use serde::{Deserialize, Serialize};
 
 use rustic_core::{
-    BackupOptions, ConfigOptions, KeyOptions, LocalSourceFilterOptions, LocalSourceSaveOptions,
-    ParentOptions, PathList, Repository, SnapshotOptions,
+    repofile::SnapshotFile, BackupOptions, ConfigOptions, Id, KeyOptions, LocalSourceFilterOptions,
+    LocalSourceSaveOptions, LsOptions, ParentOptions, PathList, Repository, RusticResult,
+    SnapshotGroupCriterion, SnapshotOptions, StringList,
 };
 
 use super::init::init;
@@ -43,10 +68,34 @@ pub struct BackupCmd {
     #[merge(skip)]
     stdin_filename: String,
 
+    /// Call the given command and backup its output like reading from stdin. Note: This replaces the
+    /// source. If the command is not successful, the backup fails and the already written snapshot
+    /// is removed again; if rustic is interrupted before, this snapshot may remain in the repository.
+    #[clap(long, value_name = "COMMAND")]
+    stdin_command: Option<String>,
+
     /// Manually set backup path in snapshot
     #[clap(long, value_name = "PATH")]
     as_path: Option<PathBuf>,
 
+    /// Read paths to backup from the given file, one path per line. Empty lines and lines starting
+    /// with '#' are ignored, leading and trailing whitespace is removed (can be specified multiple times)
+    #[clap(long, value_name = "FILE")]
+    #[merge(strategy = merge::vec::overwrite_empty)]
+    files_from: Vec<PathBuf>,
+
+    /// Read paths to backup from the given file, one path per line. Only empty lines are ignored
+    /// (can be specified multiple times)
+    #[clap(long, value_name = "FILE")]
+    #[merge(strategy = merge::vec::overwrite_empty)]
+    files_from_verbatim: Vec<PathBuf>,
+
+    /// Read paths to backup from the given file, paths are separated by NUL bytes and must be valid
+    /// unicode (can be specified multiple times)
+    #[clap(long, value_name = "FILE")]
+    #[merge(strategy = merge::vec::overwrite_empty)]
+    files_from_raw: Vec<PathBuf>,
+
     /// Ignore save options
     #[clap(flatten)]
     #[serde(flatten)]
@@ -57,6 +106,22 @@ pub struct BackupCmd {
     #[merge(strategy = merge::bool::overwrite_false)]
     json: bool,
 
+    /// Print the paths which have been added, modified or removed compared to the parent snapshot
+    #[clap(long)]
+    #[merge(strategy = merge::bool::overwrite_false)]
+    print_changes: bool,
+
+    /// Don't save a snapshot if its tree would be identical to the tree of the parent snapshot.
+    /// Note: This needs an additional dry-run backup, so changed files are read twice. For stdin,
+    /// the snapshot is saved and removed again
+    #[clap(long)]
+    #[merge(strategy = merge::bool::overwrite_false)]
+    skip_if_unchanged: bool,
+
+    /// Number of sources to backup in parallel [default: 1]
+    #[clap(long, value_name = "N")]
+    parallel_sources: Option<usize>,
+
     /// Initialize repository, if it doesn't exist yet
     #[clap(long)]
     #[merge(strategy = merge::bool::overwrite_false)]
@@ -77,6 +142,12 @@ pub struct BackupCmd {
     #[serde(flatten)]
     snap_opts: SnapshotOptions,
 
+    /// Hooks. The commands are called with the environment variable `RUSTIC_BACKUP_SOURCE` and,
+    /// if available, `RUSTIC_SNAPSHOT_ID` or `RUSTIC_BACKUP_ERROR` set.
+    #[clap(flatten, next_help_heading = "Hooks")]
+    #[serde(flatten)]
+    hooks: Hooks,
+
     /// Key options (when using --init)
     #[clap(flatten, next_help_heading = "Key options (when using --init)")]
     #[serde(skip)]
@@ -115,15 +186,62 @@ pub(crate) fn merge_sources(left: &mut Vec<BackupCmd>, mut right: Vec<BackupCmd>
 
 impl Runnable for BackupCmd {
     fn run(&self) {
-        if let Err(err) = self.inner_run() {
-            status_err!("{}", err);
-            RUSTIC_APP.shutdown(Shutdown::Crash);
+        match self.inner_run() {
+            Ok(0) => {}
+            Ok(errors) => {
+                status_warn!(
+                    "{} error(s) occurred during backup, snapshot(s) are incomplete!",
+                    errors
+                );
+                // `shutdown` always exits with code 0, so shut down the components manually
+                let shutdown = RUSTIC_APP
+                    .state()
+                    .components()
+                    .shutdown(&*RUSTIC_APP, Shutdown::Graceful);
+                if let Err(err) = shutdown {
+                    status_err!("{}", err);
+                }
+                std::process::exit(EXIT_CODE_INCOMPLETE);
+            }
+            Err(err) => {
+                status_err!("{}", err);
+                RUSTIC_APP.shutdown(Shutdown::Crash);
+            }
         };
     }
 }
 
 impl BackupCmd {
-    fn inner_run(&self) -> Result<()> {
+    /// Create a backup command for the given source, e.g. a source from the config file
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the source, paths are separated like in the config file
+    ///
+    /// # Errors
+    ///
+    /// If the source cannot be split into paths
+    pub(crate) fn with_source(source: &str) -> Result<Self> {
+        Ok(Self {
+            cli_sources: shell_words::split(source)
+                .with_context(|| format!("error parsing source {source:?}"))?,
+            stdin_filename: "stdin".to_string(),
+            ..Default::default()
+        })
+    }
+
+    /// Run the backup
+    ///
+    /// If the backup of a source fails, the remaining sources are still backed up.
+    ///
+    /// # Returns
+    ///
+    /// The number of errors which occurred during backup but did not abort it
+    ///
+    /// # Errors
+    ///
+    /// If the backup of any source failed
+    pub(crate) fn inner_run(&self) -> Result<usize> {
         let config = RUSTIC_APP.config();
 
         let po = config.global.progress_options;
@@ -141,6 +259,15 @@ impl BackupCmd {
             open_repository(&config)?
         }
         .to_indexed_ids()?;
+        let backup_repo = BackupRepo {
+            backup: Box::new(
+                |opts: &BackupOptions, source: PathList, snap: SnapshotFile| {
+                    repo.backup(opts, source, snap)
+                },
+            ),
+            get_snapshot: Box::new(|id: Id| Ok(repo.get_snapshots(&[id.to_string()])?.remove(0))),
+            delete_snapshot: Box::new(|id: Id| repo.delete_snapshots(&[id])),
+        };
 
         // manually check for a "source" field, check is not done by serde, see above.
         if !config.backup.source.is_empty() {
@@ -157,12 +284,9 @@ impl BackupCmd {
         let config_sources: Vec<_> = config_opts
             .iter()
             .map(|opt| -> Result<_> {
-                Ok(PathList::from_string(&opt.source)?
-                    .sanitize()
-                    .with_context(|| {
-                        format!("error sanitizing source=\"{}\" in config file", opt.source)
-                    })?
-                    .merge())
+                BackupSource::from_string(&opt.source).with_context(|| {
+                    format!("error sanitizing source=\"{}\" in config file", opt.source)
+                })
             })
             .filter_map(|p| match p {
                 Ok(paths) => Some(paths),
@@ -173,10 +297,18 @@ impl BackupCmd {
             })
             .collect();
 
+        if !self.cli_sources.is_empty() && self.stdin_command.is_some() {
+            bail!("stdin-command cannot be used with sources given on the command line!");
+        }
+
         let sources = match (self.cli_sources.is_empty(), config_opts.is_empty()) {
-            (false, _) => {
-                let item = PathList::from_strings(&self.cli_sources).sanitize()?;
-                vec![item]
+            (false, _) => vec![BackupSource::new(self.cli_sources.clone())?],
+            (true, _) if self.stdin_command.is_some() => {
+                vec![BackupSource::new(vec!["-".to_string()])?]
+            }
+            // paths given by files-from are used instead of the sources in the config file
+            (true, _) if self.has_files_from() || config.backup.has_files_from() => {
+                vec![BackupSource::new(Vec::new())?]
             }
             (true, false) => {
                 info!("using all backup sources from config file.");
@@ -187,72 +319,852 @@ impl BackupCmd {
             }
         };
 
-        for source in sources {
-            let mut opts = self.clone();
+        let parallel_sources = self
+            .parallel_sources
+            .or(config.backup.parallel_sources)
+            .unwrap_or(1);
+
+        // errors which cannot be attributed to a single source
+        let mut unattributed_errors = Vec::new();
+        let results: Vec<_> = if parallel_sources > 1 && sources.len() > 1 {
+            info!("backing up {parallel_sources} sources in parallel.");
+            // errors are collected from all threads, so they can only be collected for all sources
+            let collection = ErrorCollector::start();
+            let (tx, rx) = crossbeam_channel::unbounded();
+            for item in sources.into_iter().enumerate() {
+                tx.send(item)?;
+            }
+            drop(tx);
+
+            let mut results: Vec<_> = std::thread::scope(|scope| {
+                // all workers must be spawned before joining the first one, so don't join lazily
+                #[allow(clippy::needless_collect)]
+                let workers: Vec<_> = (0..parallel_sources)
+                    .map(|_| {
+                        scope.spawn(|| {
+                            rx.iter()
+                                .map(|(idx, source)| {
+                                    let result = self.backup_source(
+                                        &backup_repo,
+                                        &config,
+                                        &source,
+                                        &config_sources,
+                                        true,
+                                    );
+                                    (idx, (source, result))
+                                })
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                    .collect();
+                workers
+                    .into_iter()
+                    .flat_map(|worker| worker.join().expect("backup thread panicked!"))
+                    .collect()
+            });
+            unattributed_errors = collection.finish();
+            // keep the order of the sources for the output
+            results.sort_by_key(|(idx, _)| *idx);
+            results.into_iter().map(|(_, result)| result).collect()
+        } else {
+            sources
+                .into_iter()
+                .map(|source| {
+                    let result =
+                        self.backup_source(&backup_repo, &config, &source, &config_sources, false);
+                    (source, result)
+                })
+                .collect()
+        };
+
+        let mut failed = 0;
+        let results: Vec<_> = results
+            .into_iter()
+            .filter_map(|(source, result)| match result {
+                Ok(result) => Some(result),
+                Err(err) => {
+                    error!("backup of {source} failed: {err:#}");
+                    failed += 1;
+                    None
+                }
+            })
+            .collect();
+
+        // print a single snapshot as object and multiple snapshots as array to get valid json
+        let json_results: Vec<_> = results.iter().filter(|result| result.json).collect();
+        let mut stdout = std::io::stdout();
+        match json_results.as_slice() {
+            [] => {}
+            [result] => serde_json::to_writer_pretty(&mut stdout, result)?,
+            json_results => serde_json::to_writer_pretty(&mut stdout, json_results)?,
+        }
+
+        if !unattributed_errors.is_empty() {
+            warn!(
+                "{} error(s) occurred during parallel backup, snapshot(s) may be incomplete:",
+                unattributed_errors.len()
+            );
+            for error in &unattributed_errors {
+                warn!("  {error}");
+            }
+        }
+
+        if failed > 0 {
+            bail!("backup of {failed} source(s) failed!");
+        }
+        let errors: usize = results.iter().map(|result| result.errors.len()).sum();
+        Ok(errors + unattributed_errors.len())
+    }
+
+    /// Backup a single source
+    ///
+    /// The options are merged from the command line, the source-specific section and the
+    /// `[backup]` section of the config file. The hooks are run around the actual backup.
+    ///
+    /// # Arguments
+    ///
+    /// * `repo` - the repository to backup to
+    /// * `config` - the rustic config
+    /// * `source` - the source to backup
+    /// * `config_sources` - the sanitized sources given in the config file
+    /// * `parallel` - whether sources are backed up in parallel
+    ///
+    /// # Returns
+    ///
+    /// The saved snapshot together with the errors which occurred during the backup
+    fn backup_source(
+        &self,
+        repo: &BackupRepo<'_>,
+        config: &Arc<RusticConfig>,
+        source: &BackupSource,
+        config_sources: &[BackupSource],
+        parallel: bool,
+    ) -> Result<BackupResult> {
+        let config_opts = &config.backup.sources;
+        let mut opts = self.clone();
 
-            // merge Options from config file, if given
-            if let Some(idx) = config_sources.iter().position(|s| s == &source) {
-                info!("merging source={source} section from config file");
-                opts.merge(config_opts[idx].clone());
+        // merge Options from config file, if given
+        if let Some(idx) = config_sources.iter().position(|s| s.paths == source.paths) {
+            info!("merging source={source} section from config file");
+            opts.merge(config_opts[idx].clone());
+        }
+        if let Some(path) = &opts.as_path {
+            // as_path only works in combination with a single target
+            if source.paths.len() > 1 {
+                bail!("as-path only works with a single target!");
             }
-            if let Some(path) = &opts.as_path {
-                // as_path only works in combination with a single target
-                if source.len() > 1 {
-                    bail!("as-path only works with a single target!");
+            // merge Options from config file using as_path, if given
+            if let Some(path) = path.as_os_str().to_str() {
+                if let Some(idx) = config_opts.iter().position(|opt| opt.source == path) {
+                    info!("merging source=\"{path}\" section from config file");
+                    opts.merge(config_opts[idx].clone());
                 }
-                // merge Options from config file using as_path, if given
-                if let Some(path) = path.as_os_str().to_str() {
-                    if let Some(idx) = config_opts.iter().position(|opt| opt.source == path) {
-                        info!("merging source=\"{path}\" section from config file");
-                        opts.merge(config_opts[idx].clone());
+            }
+        }
+
+        // merge "backup" section from config file, if given
+        opts.merge(config.backup.clone());
+
+        let source = opts.add_files_from(source)?;
+        if opts.as_path.is_some() && source.paths.len() > 1 {
+            bail!("as-path only works with a single target!");
+        }
+        if opts.has_files_from() && opts.parent_opts.group_by.is_none() {
+            // the paths given by files-from usually change between backups, so don't use them to
+            // find the parent snapshot
+            opts.parent_opts.group_by = Some(SnapshotGroupCriterion::from_str("host,label")?);
+        }
+
+        opts.expand_snap_opts(&self.snap_opts, &source);
+
+        let hooks = opts.hooks.clone();
+        let json = opts.json;
+        let print_changes = opts.print_changes;
+        let mut env = vec![("RUSTIC_BACKUP_SOURCE", source.to_string())];
+
+        // when backing up in parallel, errors are collected for all sources in `inner_run`
+        let collection = (!parallel).then(ErrorCollector::start);
+        let result = hooks
+            .run_before(&env)
+            .and_then(|()| opts.backup(repo, config, &source));
+        let errors = collection.map(ErrorCollection::finish).unwrap_or_default();
+
+        let result = result.and_then(|(snapshot, skipped)| {
+            let mut changes = None;
+            if print_changes && !skipped {
+                if config.global.dry_run {
+                    warn!("cannot determine changed paths in dry-run mode.");
+                } else {
+                    changes = Some(snapshot_changes(config, &snapshot)?);
+                }
+            }
+            Ok(BackupResult {
+                snapshot,
+                skipped,
+                errors,
+                changes,
+                json,
+            })
+        });
+        match &result {
+            Ok(result) => {
+                if !json {
+                    // name the source, as the summaries of parallel backups are printed in any order
+                    print_summary(result, parallel.then_some(&source))?;
+                }
+                if !result.skipped {
+                    env.push(("RUSTIC_SNAPSHOT_ID", result.snapshot.id.to_string()));
+                }
+            }
+            Err(err) => {
+                env.push(("RUSTIC_BACKUP_ERROR", format!("{err:#}")));
+                hooks.run_on_failure(&env);
+            }
+        }
+        let result = result.and_then(|result| hooks.run_after(&env).map(|()| result));
+        let finally = hooks.run_finally(&env);
+        let result = result?;
+        finally?;
+
+        info!("backup of {source} done.");
+        Ok(result)
+    }
+
+    /// Check if paths to backup are given by files
+    fn has_files_from(&self) -> bool {
+        !self.files_from.is_empty()
+            || !self.files_from_verbatim.is_empty()
+            || !self.files_from_raw.is_empty()
+    }
+
+    /// Add the paths given by `files-from`, `files-from-verbatim` and `files-from-raw` to a source
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the source to add the paths to
+    ///
+    /// # Errors
+    ///
+    /// If a file cannot be read, contains a non-unicode path or the paths cannot be sanitized
+    fn add_files_from(&self, source: &BackupSource) -> Result<BackupSource> {
+        if !self.has_files_from() {
+            return Ok(source.clone());
+        }
+
+        let mut paths = source.given.clone();
+        for file in &self.files_from {
+            let content = std::fs::read_to_string(file)
+                .with_context(|| format!("error reading files-from file {file:?}"))?;
+            paths.extend(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_string),
+            );
+        }
+        for file in &self.files_from_verbatim {
+            let content = std::fs::read_to_string(file)
+                .with_context(|| format!("error reading files-from-verbatim file {file:?}"))?;
+            paths.extend(
+                content
+                    .lines()
+                    .filter(|line| !line.is_empty())
+                    .map(str::to_string),
+            );
+        }
+        for file in &self.files_from_raw {
+            let content = std::fs::read(file)
+                .with_context(|| format!("error reading files-from-raw file {file:?}"))?;
+            for path in content.split(|c| *c == 0).filter(|path| !path.is_empty()) {
+                let path = String::from_utf8(path.to_vec())
+                    .with_context(|| format!("non-unicode path in files-from-raw file {file:?}"))?;
+                paths.push(path);
+            }
+        }
+        if paths.is_empty() {
+            bail!("no paths to backup given in files-from files.");
+        }
+
+        BackupSource::new(paths)
+    }
+
+    /// Expand the placeholders in label, tags, description and host of the snapshot options
+    ///
+    /// Only values from the config file are expanded; values given on the command line are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `cli_opts` - the snapshot options given on the command line
+    /// * `source` - the source to backup
+    fn expand_snap_opts(&mut self, cli_opts: &SnapshotOptions, source: &BackupSource) {
+        let path = match (&self.as_path, source.is_stdin()) {
+            (Some(as_path), _) => Some(as_path.clone()),
+            (None, true) => Some(PathBuf::from(&self.stdin_filename)),
+            (None, false) => source.given.first().map(PathBuf::from),
+        };
+        let placeholders = Placeholders::new(source.to_string(), path);
+
+        let snap_opts = &mut self.snap_opts;
+        for (value, cli_value) in [
+            (&mut snap_opts.label, &cli_opts.label),
+            (&mut snap_opts.description, &cli_opts.description),
+            (&mut snap_opts.host, &cli_opts.host),
+        ] {
+            if let (Some(value), None) = (value, cli_value) {
+                *value = placeholders.expand(value);
+            }
+        }
+        // tags given on the command line replace all tags from the config file
+        if cli_opts.tag.is_empty() {
+            for tags in &mut snap_opts.tag {
+                *tags = placeholders.expand_tags(tags);
+            }
+        }
+    }
+
+    /// Run the backup of a source using the (already merged) options
+    ///
+    /// # Arguments
+    ///
+    /// * `repo` - the repository to backup to
+    /// * `config` - the rustic config
+    /// * `source` - the source to backup
+    ///
+    /// # Returns
+    ///
+    /// The saved snapshot and whether it has been skipped as nothing changed
+    fn backup(
+        self,
+        repo: &BackupRepo<'_>,
+        config: &RusticConfig,
+        source: &BackupSource,
+    ) -> Result<(SnapshotFile, bool)> {
+        let backup_opts = BackupOptions::default()
+            .stdin_filename(self.stdin_filename)
+            .as_path(self.as_path)
+            .parent_opts(self.parent_opts)
+            .ignore_save_opts(self.ignore_save_opts)
+            .ignore_filter_opts(self.ignore_filter_opts)
+            .dry_run(config.global.dry_run);
+        let snap_opts = self.snap_opts.to_snapshot()?;
+
+        if self.skip_if_unchanged && !source.is_stdin() && !config.global.dry_run {
+            // check for changes without saving anything, so no snapshot needs to be removed again
+            let dry_run_opts = backup_opts.clone().dry_run(true);
+            let snap = (repo.backup)(&dry_run_opts, source.paths.clone(), snap_opts.clone())?;
+            if is_unchanged(repo, &snap)? {
+                return Ok((snap, true));
+            }
+        }
+
+        let snap = match &self.stdin_command {
+            None => (repo.backup)(&backup_opts, source.paths.clone(), snap_opts)?,
+            Some(command) => {
+                let (result, status) = StdinCommand::spawn(command)?
+                    .run(|| (repo.backup)(&backup_opts, source.paths.clone(), snap_opts));
+                let snap = result?;
+                if let Err(err) = status {
+                    // Note: rustic_core has already saved the snapshot, so it can only be removed
+                    // again, see the docs of `stdin_command`.
+                    if !config.global.dry_run {
+                        (repo.delete_snapshot)(snap.id)?;
                     }
+                    return Err(err.context("snapshot has not been saved"));
                 }
+                snap
             }
+        };
 
-            // merge "backup" section from config file, if given
-            opts.merge(config.backup.clone());
-
-            let backup_opts = BackupOptions::default()
-                .stdin_filename(opts.stdin_filename)
-                .as_path(opts.as_path)
-                .parent_opts(opts.parent_opts)
-                .ignore_save_opts(opts.ignore_save_opts)
-                .ignore_filter_opts(opts.ignore_filter_opts)
-                .dry_run(config.global.dry_run);
-            let snap = repo.backup(&backup_opts, source.clone(), opts.snap_opts.to_snapshot()?)?;
-
-            if opts.json {
-                let mut stdout = std::io::stdout();
-                serde_json::to_writer_pretty(&mut stdout, &snap)?;
-            } else {
-                let summary = snap.summary.unwrap();
-                println!(
-                    "Files:       {} new, {} changed, {} unchanged",
-                    summary.files_new, summary.files_changed, summary.files_unmodified
-                );
-                println!(
-                    "Dirs:        {} new, {} changed, {} unchanged",
-                    summary.dirs_new, summary.dirs_changed, summary.dirs_unmodified
-                );
-                debug!("Data Blobs:  {} new", summary.data_blobs);
-                debug!("Tree Blobs:  {} new", summary.tree_blobs);
-                println!(
-                    "Added to the repo: {} (raw: {})",
-                    bytes_size_to_string(summary.data_added_packed),
-                    bytes_size_to_string(summary.data_added)
-                );
+        // Note: When reading from stdin, the input cannot be read twice. So the snapshot has
+        // already been saved and needs to be removed again.
+        let skipped = self.skip_if_unchanged && is_unchanged(repo, &snap)?;
+        if skipped && !config.global.dry_run {
+            (repo.delete_snapshot)(snap.id)?;
+        }
 
-                println!(
-                    "processed {} files, {}",
-                    summary.total_files_processed,
-                    bytes_size_to_string(summary.total_bytes_processed)
-                );
-                println!("snapshot {} successfully saved.", snap.id);
+        Ok((snap, skipped))
+    }
+}
+
+/// Check if the tree of a snapshot is identical to the tree of its parent snapshot
+///
+/// # Arguments
+///
+/// * `repo` - the repository
+/// * `snap` - the snapshot
+fn is_unchanged(repo: &BackupRepo<'_>, snap: &SnapshotFile) -> Result<bool> {
+    let Some(parent) = snap.parent else {
+        return Ok(false);
+    };
+    let parent = (repo.get_snapshot)(parent)?;
+    Ok(parent.tree == snap.tree)
+}
+
+/// A source to backup
+///
+/// `PathList` doesn't give access to its paths, so the paths are also kept as they are given. They
+/// are needed to add the paths given by files-from and to expand placeholders.
+#[derive(Clone, Debug)]
+struct BackupSource {
+    /// the paths as given on the command line, in the config file or by files-from
+    given: Vec<String>,
+    /// the sanitized paths
+    paths: PathList,
+}
+
+impl BackupSource {
+    /// Create a source from the given paths
+    ///
+    /// # Arguments
+    ///
+    /// * `given` - the paths of the source
+    ///
+    /// # Errors
+    ///
+    /// If the paths cannot be sanitized
+    fn new(given: Vec<String>) -> Result<Self> {
+        let paths = PathList::from_strings(&given).sanitize()?;
+        Ok(Self { given, paths })
+    }
+
+    /// Create a source from a string, paths are separated like in the config file
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the source, see `PathList::from_string`
+    ///
+    /// # Errors
+    ///
+    /// If the source cannot be split into paths or the paths cannot be sanitized
+    fn from_string(source: &str) -> Result<Self> {
+        Self::new(shell_words::split(source)?)
+    }
+
+    /// Check if the source is stdin, i.e. the output of a stdin-command or the single path `-`
+    fn is_stdin(&self) -> bool {
+        self.given == ["-"]
+    }
+}
+
+impl Display for BackupSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Note: formatting an empty `PathList` panics; a source is only empty before the paths
+        // given by files-from are added.
+        if self.paths.is_empty() {
+            f.write_str("files-from")
+        } else {
+            write!(f, "{}", self.paths)
+        }
+    }
+}
+
+/// Function to backup a source, see `Repository::backup`
+type BackupFn<'a> =
+    Box<dyn Fn(&BackupOptions, PathList, SnapshotFile) -> RusticResult<SnapshotFile> + Sync + 'a>;
+
+/// Operations on the repository which are needed to backup a source
+///
+/// `rustic_core` doesn't export the trait of indexed repositories, so functions cannot take an
+/// indexed repository as argument. Instead, the needed operations are given as closures.
+struct BackupRepo<'a> {
+    /// backup a source
+    backup: BackupFn<'a>,
+    /// get a snapshot by its id
+    get_snapshot: Box<dyn Fn(Id) -> RusticResult<SnapshotFile> + Sync + 'a>,
+    /// delete a snapshot by its id
+    delete_snapshot: Box<dyn Fn(Id) -> RusticResult<()> + Sync + 'a>,
+}
+
+/// Values for placeholders which can be used in the snapshot options
+///
+/// Available placeholders are `{hostname}`, `{source}`, `{source_basename}`, `{date}`, `{time}`
+/// and `{env:NAME}` for the environment variable `NAME`. Everything else is kept as it is.
+#[derive(Debug)]
+struct Placeholders {
+    /// the source to backup
+    source: String,
+    /// the (first) path of the source or the path given by as-path
+    path: Option<PathBuf>,
+    /// the current time
+    now: DateTime<Local>,
+}
+
+impl Placeholders {
+    /// Create new placeholder values
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the source to backup
+    /// * `path` - the (first) path of the source or the path given by as-path
+    fn new(source: String, path: Option<PathBuf>) -> Self {
+        Self {
+            source,
+            path,
+            now: Local::now(),
+        }
+    }
+
+    /// Get the value of a placeholder
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the placeholder without braces
+    ///
+    /// # Returns
+    ///
+    /// `None` if there is no such placeholder or the environment variable is not set
+    fn value(&self, name: &str) -> Option<String> {
+        Some(match name {
+            "hostname" => gethostname::gethostname().to_string_lossy().to_string(),
+            "source" => self.source.clone(),
+            "source_basename" => self
+                .path
+                .as_ref()
+                .and_then(|path| path.file_name())
+                .map_or_else(String::new, |name| name.to_string_lossy().to_string()),
+            "date" => self.now.format("%Y-%m-%d").to_string(),
+            "time" => self.now.format("%H:%M:%S").to_string(),
+            name => {
+                let var = name.strip_prefix("env:")?;
+                match std::env::var(var) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        warn!("not replacing placeholder {{{name}}}: {err}");
+                        return None;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Expand all placeholders in a string
+    ///
+    /// Braces which don't form a known placeholder are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - the string to expand
+    fn expand(&self, s: &str) -> String {
+        let mut result = String::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            result.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let value = rest
+                .find('}')
+                .and_then(|end| Some((self.value(&rest[1..end])?, end)));
+            match value {
+                Some((value, end)) => {
+                    result.push_str(&value);
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    result.push('{');
+                    rest = &rest[1..];
+                }
             }
+        }
+        result.push_str(rest);
+        result
+    }
 
-            info!("backup of {source} done.");
+    /// Expand all placeholders in each tag of a list of tags
+    ///
+    /// # Arguments
+    ///
+    /// * `tags` - the tags to expand
+    fn expand_tags(&self, tags: &StringList) -> StringList {
+        let mut expanded = StringList::default();
+        for tag in tags.iter() {
+            expanded.add(self.expand(tag));
         }
+        expanded
+    }
+}
 
+/// Result of the backup of a single source
+#[derive(Debug, Serialize)]
+struct BackupResult {
+    /// the saved snapshot
+    #[serde(flatten)]
+    snapshot: SnapshotFile,
+    /// whether the snapshot has not been kept as nothing changed compared to its parent
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    skipped: bool,
+    /// errors which occurred during the backup; if not empty, the snapshot is incomplete
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<String>,
+    /// changed paths compared to the parent snapshot, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    changes: Option<Vec<Change>>,
+    /// whether the result should be output in json format
+    #[serde(skip)]
+    json: bool,
+}
+
+/// A path which has been changed compared to the parent snapshot
+#[derive(Debug, Serialize)]
+struct Change {
+    /// type of the change
+    #[serde(rename = "type")]
+    tpe: DiffType,
+    /// the changed path
+    path: PathBuf,
+}
+
+/// Get the paths which have been changed in a snapshot compared to its parent
+///
+/// Without parent, all paths are reported as added.
+///
+/// # Arguments
+///
+/// * `config` - the rustic config
+/// * `snap` - the snapshot
+fn snapshot_changes(config: &Arc<RusticConfig>, snap: &SnapshotFile) -> Result<Vec<Change>> {
+    // the index of the repository used for the backup doesn't contain the newly saved trees,
+    // so the repository needs to be opened again
+    let repo = open_repository(config)?.to_indexed_ids()?;
+    let ls_opts = LsOptions {
+        recursive: true,
+        ..Default::default()
+    };
+
+    let parent_node = snap
+        .parent
+        .map(|id| -> Result<_> {
+            let parent = repo.get_snapshots(&[id.to_string()])?.remove(0);
+            Ok(repo.node_from_snapshot_and_path(&parent, "")?)
+        })
+        .transpose()?;
+    let parent_nodes = parent_node
+        .map(|node| repo.ls(&node, &ls_opts))
+        .transpose()?
+        .into_iter()
+        .flatten();
+    let node = repo.node_from_snapshot_and_path(snap, "")?;
+
+    let mut changes = Vec::new();
+    diff(
+        parent_nodes,
+        repo.ls(&node, &ls_opts)?,
+        false,
+        |_path, node1, node2| Ok(node1.content == node2.content),
+        false,
+        |tpe, path| {
+            changes.push(Change {
+                tpe,
+                path: path.to_path_buf(),
+            });
+        },
+    )?;
+    Ok(changes)
+}
+
+/// Print the summary of a saved snapshot
+///
+/// stdout is locked while printing, so summaries of sources which are backed up in parallel don't mix.
+///
+/// # Arguments
+///
+/// * `result` - the result of the backup
+/// * `source` - the source to name in the summary, if any
+fn print_summary(result: &BackupResult, source: Option<&BackupSource>) -> Result<()> {
+    let snap = &result.snapshot;
+    let errors = &result.errors;
+    let summary = snap.summary.as_ref().unwrap();
+    let mut stdout = std::io::stdout().lock();
+    if let Some(source) = source {
+        writeln!(stdout, "Source:      {source}")?;
+    }
+    for change in result.changes.iter().flatten() {
+        writeln!(stdout, "{}    {:?}", change.tpe.symbol(), change.path)?;
+    }
+    writeln!(
+        stdout,
+        "Files:       {} new, {} changed, {} unchanged",
+        summary.files_new, summary.files_changed, summary.files_unmodified
+    )?;
+    writeln!(
+        stdout,
+        "Dirs:        {} new, {} changed, {} unchanged",
+        summary.dirs_new, summary.dirs_changed, summary.dirs_unmodified
+    )?;
+    debug!("Data Blobs:  {} new", summary.data_blobs);
+    debug!("Tree Blobs:  {} new", summary.tree_blobs);
+    writeln!(
+        stdout,
+        "Added to the repo: {} (raw: {})",
+        bytes_size_to_string(summary.data_added_packed),
+        bytes_size_to_string(summary.data_added)
+    )?;
+
+    writeln!(
+        stdout,
+        "processed {} files, {}",
+        summary.total_files_processed,
+        bytes_size_to_string(summary.total_bytes_processed)
+    )?;
+    if result.skipped {
+        writeln!(
+            stdout,
+            "nothing changed compared to parent snapshot {}, no snapshot saved.",
+            snap.parent.unwrap()
+        )?;
+    } else if errors.is_empty() {
+        writeln!(stdout, "snapshot {} successfully saved.", snap.id)?;
+    } else {
+        writeln!(
+            stdout,
+            "snapshot {} saved, but incomplete due to {} error(s):",
+            snap.id,
+            errors.len()
+        )?;
+        for error in errors {
+            writeln!(stdout, "  {error}")?;
+        }
+    }
+    Ok(())
+}
+
+/// Lock to ensure that only one [`StdinCommand`] redirects stdin at a time
+#[cfg(not(windows))]
+static STDIN_LOCK: Mutex<()> = Mutex::new(());
+
+/// A command whose output replaces stdin of rustic while the command is running
+#[cfg(not(windows))]
+#[derive(Debug)]
+struct StdinCommand {
+    /// lock on stdin, released after the original stdin has been restored
+    _lock: MutexGuard<'static, ()>,
+    /// the command as given by the user
+    command: String,
+    /// the running child process
+    child: Child,
+    /// duplicate of the original stdin, restored by `finish`
+    saved_stdin: RawFd,
+}
+
+#[cfg(not(windows))]
+impl StdinCommand {
+    /// Spawn the command and use its stdout as stdin
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - the command to call
+    ///
+    /// # Errors
+    ///
+    /// If the command cannot be parsed or started or redirecting stdin fails
+    fn spawn(command: &str) -> Result<Self> {
+        let args = shell_words::split(command)
+            .with_context(|| format!("error parsing stdin-command {command:?}"))?;
+        let Some((program, args)) = args.split_first() else {
+            bail!("stdin-command is empty!");
+        };
+        let lock = STDIN_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        debug!("calling stdin-command {command:?}");
+        let mut child = std::process::Command::new(program)
+            .args(args)
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("error calling stdin-command {command:?}"))?;
+        let stdout = child
+            .stdout
+            .take()
+            .context("stdout of stdin-command is not available")?;
+        let saved_stdin = dup(STDIN_FILENO)?;
+        _ = dup2(stdout.as_raw_fd(), STDIN_FILENO)?;
+
+        Ok(Self {
+            _lock: lock,
+            command: command.to_string(),
+            child,
+            saved_stdin,
+        })
+    }
+
+    /// Restore the original stdin and wait for the command to finish
+    ///
+    /// # Errors
+    ///
+    /// If restoring stdin fails or the command did not exit successfully
+    fn finish(mut self) -> Result<()> {
+        _ = dup2(self.saved_stdin, STDIN_FILENO)?;
+        close(self.saved_stdin)?;
+        let status = self.child.wait()?;
+        if !status.success() {
+            bail!(
+                "stdin-command {:?} was not successful: {status}",
+                self.command
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A command whose output replaces stdin - not supported on windows
+#[cfg(windows)]
+#[derive(Debug)]
+struct StdinCommand;
+
+#[cfg(windows)]
+impl StdinCommand {
+    fn spawn(_command: &str) -> Result<Self> {
+        bail!("stdin-command is not supported on windows!");
+    }
+
+    fn finish(self) -> Result<()> {
         Ok(())
     }
 }
+
+impl StdinCommand {
+    /// Run a function while stdin is replaced by the output of the command, then finish it
+    ///
+    /// # Arguments
+    ///
+    /// * `f` - the function reading from stdin
+    ///
+    /// # Returns
+    ///
+    /// The result of the function and the result of finishing the command
+    fn run<T>(self, f: impl FnOnce() -> T) -> (T, Result<()>) {
+        let result = f();
+        (result, self.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Placeholders;
+    use rustic_core::StringList;
+    use std::path::PathBuf;
+
+    #[test]
+    fn expand_placeholders() {
+        let placeholders =
+            Placeholders::new("/srv/data".to_string(), Some(PathBuf::from("/srv/data")));
+        assert_eq!(placeholders.expand("{source_basename}-x"), "data-x");
+        assert_eq!(placeholders.expand("{source}"), "/srv/data");
+        assert_eq!(placeholders.expand("{unknown}"), "{unknown}");
+        assert_eq!(placeholders.expand("{source"), "{source");
+        assert_eq!(placeholders.expand("source}"), "source}");
+        assert_eq!(placeholders.expand("{{source}}"), "{/srv/data}");
+        assert_eq!(
+            placeholders.expand(r#"{"path": "{source}"}"#),
+            r#"{"path": "/srv/data"}"#
+        );
+    }
+
+    #[test]
+    fn expand_tags() {
+        let placeholders =
+            Placeholders::new("/srv/a,b".to_string(), Some(PathBuf::from("/srv/a,b")));
+        let tags: StringList = "{source_basename},fixed,{x}".parse().unwrap();
+        let expanded = placeholders.expand_tags(&tags);
+        let mut expanded: Vec<_> = expanded.iter().cloned().collect();
+        expanded.sort();
+        assert_eq!(expanded, vec!["a,b", "fixed", "{x}"]);
+    }
+}
@@ -24,7 +24,7 @@ impl Runnable for CheckCmd {
 }
 
 impl CheckCmd {
-    fn inner_run(&self) -> Result<()> {
+    pub(crate) fn inner_run(&self) -> Result<()> {
         let config = RUSTIC_APP.config();
         let repo = open_repository(&config)?;
         repo.check(self.opts)?;
@@ -0,0 +1,373 @@
+//! `daemon` subcommand
+
+#[cfg(not(windows))]
+use std::os::fd::AsRawFd;
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::Duration,
+};
+
+use crate::{
+    commands::{backup::BackupCmd, check::CheckCmd, forget::ForgetCmd, prune::PruneCmd},
+    status_err, Application, RusticConfig, RUSTIC_APP,
+};
+
+use abscissa_core::{config::Override, Command, FrameworkError, Runnable, Shutdown};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local};
+use clap::Parser;
+use directories::ProjectDirs;
+use log::{error, info, warn};
+use merge::Merge;
+#[cfg(not(windows))]
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use sha2::{Digest, Sha256};
+
+/// `daemon` subcommand
+#[derive(clap::Parser, Command, Debug)]
+pub(crate) struct DaemonCmd {
+    /// Only run the given jobs (can be specified multiple times). If none is given, run all jobs
+    #[clap(long, value_name = "JOB")]
+    job: Vec<String>,
+
+    /// Directory to store the state of the jobs [default: dir for the repository within the
+    /// application's local data dir]
+    #[clap(long, value_name = "DIR")]
+    state_dir: Option<PathBuf>,
+}
+
+/// Daemon options
+#[derive(Default, Clone, Debug, Deserialize, Merge)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DaemonOptions {
+    /// Jobs to run
+    #[merge(strategy = merge::vec::overwrite_empty)]
+    jobs: Vec<DaemonJob>,
+}
+
+/// A job which is regularly run by the daemon
+///
+/// A job backs up the given sources and optionally runs forget, prune and check afterwards.
+#[serde_as]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DaemonJob {
+    /// Name of the job, used for logging and to remember the last run
+    name: String,
+
+    /// Interval between two runs of the job
+    #[serde_as(as = "DisplayFromStr")]
+    every: humantime::Duration,
+
+    /// Maximum random delay which is added to each scheduled run
+    #[serde(default)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    jitter: Option<humantime::Duration>,
+
+    /// Sources to backup. If empty, all sources defined in the config file are used
+    #[serde(default)]
+    sources: Vec<String>,
+
+    /// Don't run a backup within this job
+    #[serde(default)]
+    no_backup: bool,
+
+    /// Run forget (using the [forget] section) after the backup
+    #[serde(default)]
+    forget: bool,
+
+    /// Run prune after the backup and forget
+    #[serde(default)]
+    prune: bool,
+
+    /// Check the repository at the end of the job
+    #[serde(default)]
+    check: bool,
+
+    /// Number of times a failed job is retried
+    #[serde(default)]
+    retries: usize,
+
+    /// Delay before retrying a failed job [default: 5m]
+    #[serde(default)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    retry_delay: Option<humantime::Duration>,
+}
+
+impl DaemonJob {
+    /// Default delay before retrying a failed job
+    const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+    /// Get the time of the next run after the given time, including a random delay
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - the time to schedule the next run after
+    fn next_run(&self, time: DateTime<Local>) -> Result<DateTime<Local>> {
+        let jitter = self.jitter.map_or(0, |jitter| jitter.as_secs());
+        let delay = Duration::from_secs(rand::thread_rng().gen_range(0..=jitter));
+        Ok(time + chrono::Duration::from_std(delay)?)
+    }
+
+    /// Run the job and retry it as often as configured if it fails
+    ///
+    /// A retry runs all steps of the job again.
+    ///
+    /// # Errors
+    ///
+    /// If the last try failed
+    fn run_with_retries(&self) -> Result<()> {
+        let mut tries = 0;
+        loop {
+            match self.run() {
+                Err(err) if tries < self.retries => {
+                    tries += 1;
+                    let delay = self
+                        .retry_delay
+                        .map_or(Self::DEFAULT_RETRY_DELAY, |delay| *delay);
+                    warn!(
+                        "job={} status=retrying retry={tries} delay={}s error={err:#}",
+                        self.name,
+                        delay.as_secs()
+                    );
+                    sleep(delay);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Run all steps of the job
+    ///
+    /// # Errors
+    ///
+    /// If any step fails. The remaining steps are not run.
+    fn run(&self) -> Result<()> {
+        if !self.no_backup {
+            let backups = if self.sources.is_empty() {
+                vec![BackupCmd::parse_from(["backup"])]
+            } else {
+                self.sources
+                    .iter()
+                    .map(|source| BackupCmd::with_source(source))
+                    .collect::<Result<_>>()?
+            };
+            for backup in backups {
+                info!("job={} step=backup", self.name);
+                let errors = backup.inner_run()?;
+                if errors > 0 {
+                    warn!(
+                        "job={} step=backup errors={errors} snapshots are incomplete",
+                        self.name
+                    );
+                }
+            }
+        }
+        if self.forget {
+            info!("job={} step=forget", self.name);
+            ForgetCmd::parse_from(["forget"]).inner_run()?;
+        }
+        if self.prune {
+            info!("job={} step=prune", self.name);
+            PruneCmd::parse_from(["prune"]).inner_run()?;
+        }
+        if self.check {
+            info!("job={} step=check", self.name);
+            CheckCmd::parse_from(["check"]).inner_run()?;
+        }
+        Ok(())
+    }
+}
+
+impl Override<RusticConfig> for DaemonCmd {
+    // The forget section needs the same processing as when calling the forget command.
+    fn override_config(&self, config: RusticConfig) -> Result<RusticConfig, FrameworkError> {
+        ForgetCmd::parse_from(["forget"]).override_config(config)
+    }
+}
+
+impl Runnable for DaemonCmd {
+    fn run(&self) {
+        if let Err(err) = self.inner_run() {
+            status_err!("{}", err);
+            RUSTIC_APP.shutdown(Shutdown::Crash);
+        };
+    }
+}
+
+impl DaemonCmd {
+    fn inner_run(&self) -> Result<()> {
+        let config = RUSTIC_APP.config();
+
+        let jobs: Vec<_> = config
+            .daemon
+            .jobs
+            .iter()
+            .filter(|job| self.job.is_empty() || self.job.contains(&job.name))
+            .collect();
+        if jobs.is_empty() {
+            bail!("no jobs to run, please define [[daemon.jobs]] in the config file!");
+        }
+
+        let state_dir = match &self.state_dir {
+            Some(dir) => dir.clone(),
+            None => {
+                // use a separate dir for each repository, as job names are only unique per config
+                let repo = config.repository.repository.as_deref().unwrap_or_default();
+                let repo_hash = hex::encode(Sha256::digest(repo.as_bytes()));
+                ProjectDirs::from("", "", "rustic")
+                    .context("cannot determine local data dir, please use --state-dir")?
+                    .data_local_dir()
+                    .join("daemon")
+                    .join(&repo_hash[..16])
+            }
+        };
+        std::fs::create_dir_all(&state_dir)
+            .with_context(|| format!("error creating state dir {state_dir:?}"))?;
+        let mut state = DaemonState::load(&state_dir)?;
+
+        // schedule all jobs; missed runs are caught up at once
+        let now = Local::now();
+        let mut next_runs = jobs
+            .iter()
+            .map(|job| {
+                let last_run = state.last_run.get(&job.name);
+                let time = last_run.map_or(Ok(now), |last| -> Result<_> {
+                    Ok(*last + chrono::Duration::from_std(*job.every)?)
+                })?;
+                job.next_run(time)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        loop {
+            let (idx, next_run) = next_runs
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, time)| **time)
+                .map(|(idx, time)| (idx, *time))
+                .unwrap();
+            let job = jobs[idx];
+
+            if let Ok(wait) = (next_run - Local::now()).to_std() {
+                info!(
+                    "job={} status=scheduled time={}",
+                    job.name,
+                    next_run.format("%Y-%m-%d %H:%M:%S")
+                );
+                sleep(wait);
+            }
+
+            let start = Local::now();
+            match JobLock::acquire(&state_dir, &job.name)? {
+                None => warn!("job={} status=skipped reason=locked", job.name),
+                Some(_lock) => {
+                    info!("job={} status=started", job.name);
+                    match job.run_with_retries() {
+                        Ok(()) => info!(
+                            "job={} status=finished duration={}s",
+                            job.name,
+                            (Local::now() - start).num_seconds()
+                        ),
+                        Err(err) => error!("job={} status=failed error={err:#}", job.name),
+                    }
+                    // also a failed job is only run again at the next scheduled time
+
+                    _ = state.last_run.insert(job.name.clone(), start);
+                    state.save(&state_dir)?;
+                }
+            }
+
+            next_runs[idx] = job.next_run(start + chrono::Duration::from_std(*job.every)?)?;
+        }
+    }
+}
+
+/// Persistent state of the daemon
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct DaemonState {
+    /// Start time of the last run of each job
+    last_run: HashMap<String, DateTime<Local>>,
+}
+
+impl DaemonState {
+    /// Name of the file containing the state
+    const FILENAME: &'static str = "state.json";
+
+    /// Load the state from the state dir. If there is no state yet, the default state is used.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - the state dir
+    fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(Self::FILENAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let file = File::open(&path).with_context(|| format!("error opening {path:?}"))?;
+        serde_json::from_reader(file).with_context(|| format!("error reading {path:?}"))
+    }
+
+    /// Save the state to the state dir
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - the state dir
+    fn save(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(Self::FILENAME);
+        let file = File::create(&path).with_context(|| format!("error creating {path:?}"))?;
+        serde_json::to_writer_pretty(file, self)
+            .with_context(|| format!("error writing {path:?}"))?;
+        Ok(())
+    }
+}
+
+/// Lock of a job, released when dropped
+///
+/// This prevents running the same job in multiple daemons at once.
+#[derive(Debug)]
+struct JobLock(File);
+
+impl JobLock {
+    /// Try to acquire the lock for a job
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - the state dir
+    /// * `name` - the name of the job
+    ///
+    /// # Returns
+    ///
+    /// `None` if the job is already locked
+    ///
+    /// # Note
+    ///
+    /// On windows, locking is not supported and the lock is always acquired. So a job may run in
+    /// multiple daemons at once.
+    fn acquire(dir: &Path, name: &str) -> Result<Option<Self>> {
+        let path = dir.join(format!("{name}.lock"));
+        let file = File::options()
+            .create(true)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("error opening lock file {path:?}"))?;
+
+        // Note: no lock is taken on windows, see above
+        #[cfg(not(windows))]
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => {}
+            Err(Errno::EWOULDBLOCK) => return Ok(None),
+            Err(err) => return Err(err).with_context(|| format!("error locking {path:?}")),
+        }
+
+        Ok(Some(Self(file)))
+    }
+}
@@ -7,6 +7,7 @@ use abscissa_core::{Command, Runnable, Shutdown};
 use std::path::{Path, PathBuf};
 
 use anyhow::{bail, Context, Result};
+use serde::Serialize;
 
 use rustic_core::{
     repofile::{BlobType, Node, NodeType},
@@ -73,6 +74,7 @@ impl DiffCmd {
                     self.no_content,
                     |_path, node1, node2| Ok(node1.content == node2.content),
                     self.metadata,
+                    print_change,
                 )
             }
             (Some(id1), None) => {
@@ -110,6 +112,7 @@ impl DiffCmd {
                     self.no_content,
                     |path, node1, _node2| identical_content_local(&local, &repo, path, node1),
                     self.metadata,
+                    print_change,
                 )
             }
             (None, _) => {
@@ -183,7 +186,46 @@ fn identical_content_local<P, S: IndexedFull>(
     Ok(true)
 }
 
-/// Compare two streams of nodes and print the differences
+/// Type of a difference between two streams of nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum DiffType {
+    /// path only exists in the first stream
+    Removed,
+    /// path only exists in the second stream
+    Added,
+    /// node type has been changed
+    TypeChanged,
+    /// file content has been modified
+    Modified,
+    /// metadata or link target has been changed
+    MetadataChanged,
+}
+
+impl DiffType {
+    /// The symbol used to print this type of difference
+    pub(crate) const fn symbol(self) -> char {
+        match self {
+            Self::Removed => '-',
+            Self::Added => '+',
+            Self::TypeChanged => 'T',
+            Self::Modified => 'M',
+            Self::MetadataChanged => 'U',
+        }
+    }
+}
+
+/// Print a difference
+///
+/// # Arguments
+///
+/// * `tpe` - type of the difference
+/// * `path` - path which differs
+fn print_change(tpe: DiffType, path: &Path) {
+    println!("{}    {path:?}", tpe.symbol());
+}
+
+/// Compare two streams of nodes and report the differences
 ///
 /// # Arguments
 ///
@@ -192,16 +234,18 @@ fn identical_content_local<P, S: IndexedFull>(
 /// * `no_content` - don't check for different file contents
 /// * `file_identical` - function to check if the content of two files is identical
 /// * `metadata` - show differences in metadata
+/// * `report` - function called for each difference
 ///
 /// # Errors
 ///
 // TODO!: add errors!
-fn diff(
+pub(crate) fn diff(
     mut tree_streamer1: impl Iterator<Item = RusticResult<(PathBuf, Node)>>,
     mut tree_streamer2: impl Iterator<Item = RusticResult<(PathBuf, Node)>>,
     no_content: bool,
     file_identical: impl Fn(&Path, &Node, &Node) -> Result<bool>,
     metadata: bool,
+    mut report: impl FnMut(DiffType, &Path),
 ) -> Result<()> {
     let mut item1 = tree_streamer1.next().transpose()?;
     let mut item2 = tree_streamer2.next().transpose()?;
@@ -210,19 +254,19 @@ fn diff(
         match (&item1, &item2) {
             (None, None) => break,
             (Some(i1), None) => {
-                println!("-    {:?}", i1.0);
+                report(DiffType::Removed, &i1.0);
                 item1 = tree_streamer1.next().transpose()?;
             }
             (None, Some(i2)) => {
-                println!("+    {:?}", i2.0);
+                report(DiffType::Added, &i2.0);
                 item2 = tree_streamer2.next().transpose()?;
             }
             (Some(i1), Some(i2)) if i1.0 < i2.0 => {
-                println!("-    {:?}", i1.0);
+                report(DiffType::Removed, &i1.0);
                 item1 = tree_streamer1.next().transpose()?;
             }
             (Some(i1), Some(i2)) if i1.0 > i2.0 => {
-                println!("+    {:?}", i2.0);
+                report(DiffType::Added, &i2.0);
                 item2 = tree_streamer2.next().transpose()?;
             }
             (Some(i1), Some(i2)) => {
@@ -230,16 +274,16 @@ fn diff(
                 let node1 = &i1.1;
                 let node2 = &i2.1;
                 match &node1.node_type {
-                    tpe if tpe != &node2.node_type => println!("T    {path:?}"), // type was changed
+                    tpe if tpe != &node2.node_type => report(DiffType::TypeChanged, path), // type was changed
                     NodeType::File if !no_content && !file_identical(path, node1, node2)? => {
-                        println!("M    {path:?}");
+                        report(DiffType::Modified, path);
                     }
                     NodeType::File if metadata && node1.meta != node2.meta => {
-                        println!("U    {path:?}");
+                        report(DiffType::MetadataChanged, path);
                     }
                     NodeType::Symlink { .. } => {
-                        if node1.node_type.to_link() != node1.node_type.to_link() {
-                            println!("U    {path:?}");
+                        if node1.node_type.to_link() != node2.node_type.to_link() {
+                            report(DiffType::MetadataChanged, path);
                         }
                     }
                     _ => {} // no difference to show
@@ -93,7 +93,7 @@ impl Runnable for ForgetCmd {
 }
 
 impl ForgetCmd {
-    fn inner_run(&self) -> Result<()> {
+    pub(crate) fn inner_run(&self) -> Result<()> {
         let config = RUSTIC_APP.config();
         let repo = open_repository(&config)?;
 
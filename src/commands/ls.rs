@@ -2,7 +2,10 @@
 
 use std::path::Path;
 
-use crate::{commands::open_repository, status_err, Application, RUSTIC_APP};
+use crate::{
+    commands::{open_repository, restore::PathRewriteOptions},
+    status_err, Application, RUSTIC_APP,
+};
 
 use abscissa_core::{Command, Runnable, Shutdown};
 use anyhow::Result;
@@ -46,6 +49,10 @@ pub(crate) struct LsCmd {
     /// Listing options
     #[clap(flatten)]
     ls_opts: LsOptions,
+
+    /// Path rewriting options, to show the paths like restore does
+    #[clap(flatten, next_help_heading = "Path rewriting options")]
+    rewrite_opts: PathRewriteOptions,
 }
 
 impl Runnable for LsCmd {
@@ -101,6 +108,9 @@ impl LsCmd {
 
         for item in repo.ls(&node, &ls_opts)? {
             let (path, node) = item?;
+            let Some(path) = self.rewrite_opts.rewrite(&path) else {
+                continue;
+            };
             summary.update(&node);
             if self.long {
                 print_node(&node, &path);
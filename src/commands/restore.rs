@@ -1,14 +1,28 @@
 //! `restore` subcommand
 
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
 use crate::{
     commands::open_repository, helpers::bytes_size_to_string, status_err, Application, RUSTIC_APP,
 };
 
 use abscissa_core::{Command, Runnable, Shutdown};
-use anyhow::Result;
-use log::info;
+use anyhow::{bail, Context, Result};
+use itertools::Either;
+use log::{info, warn};
 
-use rustic_core::{LocalDestination, LsOptions, RestoreOptions};
+use rustic_core::{
+    repofile::{Node, NodeType},
+    IndexedFull, LocalDestination, LsOptions, ProgressBars, Repository, RestoreOptions,
+    RusticResult,
+};
+use tar::{EntryType, Header};
 
 use crate::filtering::SnapshotFilter;
 
@@ -20,10 +34,40 @@ pub(crate) struct RestoreCmd {
     #[clap(value_name = "SNAPSHOT[:PATH]")]
     snap: String,
 
-    /// Restore destination
+    /// Restore destination. When using --archive, the archive file to write (use - for stdout)
     #[clap(value_name = "DESTINATION")]
     dest: String,
 
+    /// Write an archive of the given format instead of restoring into the destination directory
+    #[clap(long, value_name = "FORMAT")]
+    archive: Option<ArchiveFormat>,
+
+    /// Map the owner given by user name or uid OLD to the user name or uid NEW (can be specified
+    /// multiple times)
+    #[clap(
+        long,
+        value_name = "OLD:NEW",
+        help_heading = "Ownership mapping options"
+    )]
+    map_user: Vec<OwnerMapping>,
+
+    /// Map the group given by group name or gid OLD to the group name or gid NEW (can be specified
+    /// multiple times)
+    #[clap(
+        long,
+        value_name = "OLD:NEW",
+        help_heading = "Ownership mapping options"
+    )]
+    map_group: Vec<OwnerMapping>,
+
+    /// Read user and group mappings from a file with lines "user OLD:NEW" or "group OLD:NEW"
+    #[clap(long, value_name = "FILE", help_heading = "Ownership mapping options")]
+    map_file: Option<PathBuf>,
+
+    /// Path rewriting options
+    #[clap(flatten, next_help_heading = "Path rewriting options")]
+    rewrite_opts: PathRewriteOptions,
+
     /// Restore options
     #[clap(flatten)]
     opts: RestoreOptions,
@@ -54,13 +98,40 @@ impl RestoreCmd {
         let dry_run = config.global.dry_run;
         let repo = open_repository(&config)?.to_indexed()?;
 
-        let node =
+        let owner_map = self.owner_map()?;
+
+        let mut node =
             repo.node_from_snapshot_path(&self.snap, |sn| config.snapshot_filter.matches(sn))?;
+        owner_map.apply(&mut node);
+        if !self.rewrite_opts.is_empty() && !node.is_dir() {
+            bail!("path rewriting only works when restoring a directory!");
+        }
 
         // for restore, always recurse into tree
         let mut ls_opts = self.ls_opts.clone();
         ls_opts.recursive = true;
-        let ls = repo.ls(&node, &ls_opts)?;
+        let ls = repo.ls(&node, &ls_opts)?.map(|item| {
+            item.map(|(path, mut node)| {
+                owner_map.apply(&mut node);
+                (path, node)
+            })
+        });
+        let ls = if self.rewrite_opts.is_empty() {
+            Either::Left(ls)
+        } else {
+            Either::Right(self.rewrite_opts.rewrite_all(ls)?.into_iter().map(Ok))
+        };
+
+        if let Some(format) = self.archive {
+            if dry_run {
+                let entries = if node.is_dir() { ls.count() } else { 1 };
+                println!("would write {entries} entries to {format} archive.");
+            } else {
+                self.write_archive(&repo, &node, ls, format)?;
+                info!("restore to {format} archive done.");
+            }
+            return Ok(());
+        }
 
         let dest = LocalDestination::new(&self.dest, true, !node.is_dir())?;
 
@@ -100,4 +171,867 @@ impl RestoreCmd {
 
         Ok(())
     }
+
+    /// Get the user and group mappings given on the command line and in the mapping file
+    fn owner_map(&self) -> Result<OwnerMap> {
+        let mut users = self.map_user.clone();
+        let mut groups = self.map_group.clone();
+        if let Some(file) = &self.map_file {
+            let content = std::fs::read_to_string(file)
+                .with_context(|| format!("error reading mapping file {file:?}"))?;
+            parse_mapping_file(&content, &mut users, &mut groups)
+                .with_context(|| format!("error in mapping file {file:?}"))?;
+        }
+        Ok(OwnerMap::new(&users, &groups))
+    }
+
+    /// Write the nodes into an archive instead of restoring them to the local filesystem
+    ///
+    /// # Arguments
+    ///
+    /// * `repo` - the repository
+    /// * `node` - the node to restore
+    /// * `ls` - the nodes within `node` to restore
+    /// * `format` - the archive format
+    fn write_archive<P: ProgressBars, S: IndexedFull>(
+        &self,
+        repo: &Repository<P, S>,
+        node: &Node,
+        ls: impl Iterator<Item = RusticResult<(PathBuf, Node)>>,
+        format: ArchiveFormat,
+    ) -> Result<()> {
+        let writer: Box<dyn Write> = if self.dest == "-" {
+            Box::new(std::io::stdout().lock())
+        } else {
+            let file = File::create(&self.dest)
+                .with_context(|| format!("error creating archive {}", self.dest))?;
+            Box::new(BufWriter::new(file))
+        };
+
+        match format {
+            ArchiveFormat::Tar => write_tar(repo, node, ls, writer)?.flush()?,
+            ArchiveFormat::TarZst => {
+                let encoder = zstd::Encoder::new(writer, 0)?;
+                write_tar(repo, node, ls, encoder)?.finish()?.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Format of an archive to restore into
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum ArchiveFormat {
+    /// tar archive
+    Tar,
+    /// zstd-compressed tar archive
+    #[value(name = "tar.zst")]
+    TarZst,
+}
+
+impl std::fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Tar => "tar",
+            Self::TarZst => "tar.zst",
+        })
+    }
+}
+
+/// Write the nodes as tar archive
+///
+/// # Arguments
+///
+/// * `repo` - the repository
+/// * `node` - the node to restore; if it is not a dir, only this node is written
+/// * `ls` - the nodes within `node`
+/// * `writer` - the writer to write the archive to
+///
+/// # Returns
+///
+/// The writer after the archive has been completely written
+fn write_tar<P: ProgressBars, S: IndexedFull, W: Write>(
+    repo: &Repository<P, S>,
+    node: &Node,
+    ls: impl Iterator<Item = RusticResult<(PathBuf, Node)>>,
+    writer: W,
+) -> Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    let mut hardlinks = HashMap::new();
+    if node.is_dir() {
+        for item in ls {
+            let (path, node) = item?;
+            append_node(&mut builder, repo, &path, &node, &mut hardlinks)?;
+        }
+    } else {
+        let path = PathBuf::from(node.name());
+        append_node(&mut builder, repo, &path, node, &mut hardlinks)?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+/// Append a node to a tar archive
+///
+/// # Arguments
+///
+/// * `builder` - the tar archive
+/// * `repo` - the repository
+/// * `path` - the path of the node within the archive
+/// * `node` - the node to append
+/// * `hardlinks` - the paths of already appended files with multiple links by (device id, inode)
+fn append_node<P: ProgressBars, S: IndexedFull, W: Write>(
+    builder: &mut tar::Builder<W>,
+    repo: &Repository<P, S>,
+    path: &Path,
+    node: &Node,
+    hardlinks: &mut HashMap<(u64, u64), PathBuf>,
+) -> Result<()> {
+    if node.node_type == NodeType::Socket {
+        warn!("cannot write socket {path:?} to archive, skipping.");
+        return Ok(());
+    }
+    let meta = &node.meta;
+    let mut header = tar_header(node)?;
+
+    match &node.node_type {
+        NodeType::File => {
+            if meta.links > 1 && meta.inode != 0 {
+                match hardlinks.entry((meta.device_id, meta.inode)) {
+                    Entry::Occupied(target) => {
+                        header.set_entry_type(EntryType::Link);
+                        header.set_size(0);
+                        builder.append_link(&mut header, path, target.get())?;
+                        return Ok(());
+                    }
+                    Entry::Vacant(entry) => _ = entry.insert(path.to_path_buf()),
+                }
+            }
+            // append_data writes the header (with extra entries for long paths) and the given data.
+            // The content is streamed from the repository, so it is written afterwards.
+            builder.append_data(&mut header, path, std::io::empty())?;
+            let mut writer = CountingWriter::new(builder.get_mut());
+            repo.dump(node, &mut writer)?;
+            if writer.count != meta.size {
+                bail!(
+                    "size of {path:?} does not match: expected {}, got {}",
+                    meta.size,
+                    writer.count
+                );
+            }
+            // pad the content to a multiple of the tar block size
+            let padding = (512 - meta.size % 512) % 512;
+            writer.write_all(&[0; 512][..padding.try_into()?])?;
+        }
+        NodeType::Symlink { .. } => {
+            builder.append_link(&mut header, path, node.node_type.to_link())?;
+        }
+        _ => builder.append_data(&mut header, path, std::io::empty())?,
+    }
+    Ok(())
+}
+
+/// Create the tar header for a node
+///
+/// The path and, for hardlinks, the entry type are set when appending the node.
+///
+/// # Arguments
+///
+/// * `node` - the node to create the header for
+///
+/// # Errors
+///
+/// If the node is a socket or a value doesn't fit into the header
+fn tar_header(node: &Node) -> Result<Header> {
+    let meta = &node.meta;
+    let mut header = Header::new_gnu();
+    let default_mode = if node.is_dir() { 0o755 } else { 0o644 };
+    header.set_mode(meta.mode.map_or(default_mode, unix_mode));
+    header.set_uid(meta.uid.unwrap_or_default().into());
+    header.set_gid(meta.gid.unwrap_or_default().into());
+    if let Some(user) = &meta.user {
+        header.set_username(user)?;
+    }
+    if let Some(group) = &meta.group {
+        header.set_groupname(group)?;
+    }
+    if let Some(mtime) = meta.mtime {
+        header.set_mtime(mtime.timestamp().try_into().unwrap_or_default());
+    }
+    header.set_size(0);
+
+    let entry_type = match &node.node_type {
+        NodeType::File => {
+            header.set_size(meta.size);
+            EntryType::Regular
+        }
+        NodeType::Dir => EntryType::Directory,
+        NodeType::Symlink { .. } => EntryType::Symlink,
+        NodeType::Dev { device } | NodeType::Chardev { device } => {
+            header.set_device_major(device_major(*device))?;
+            header.set_device_minor(device_minor(*device))?;
+            if matches!(node.node_type, NodeType::Dev { .. }) {
+                EntryType::Block
+            } else {
+                EntryType::Char
+            }
+        }
+        NodeType::Fifo => EntryType::Fifo,
+        NodeType::Socket => bail!("sockets cannot be written to tar archives"),
+    };
+    header.set_entry_type(entry_type);
+    Ok(header)
+}
+
+/// Convert a mode as saved in the repository into a unix mode
+///
+/// The repository uses the format of Go's `os.FileMode`, which has other bits for setuid, setgid
+/// and sticky. The file type bits are dropped, as the type is given by the tar entry type.
+///
+/// # Arguments
+///
+/// * `mode` - the mode as saved in the repository
+const fn unix_mode(mode: u32) -> u32 {
+    /// setuid bit of Go's `os.FileMode`
+    const GO_SETUID: u32 = 1 << 23;
+    /// setgid bit of Go's `os.FileMode`
+    const GO_SETGID: u32 = 1 << 22;
+    /// sticky bit of Go's `os.FileMode`
+    const GO_STICKY: u32 = 1 << 20;
+
+    let mut unix_mode = mode & 0o777;
+    if mode & GO_SETUID != 0 {
+        unix_mode |= 0o4000;
+    }
+    if mode & GO_SETGID != 0 {
+        unix_mode |= 0o2000;
+    }
+    if mode & GO_STICKY != 0 {
+        unix_mode |= 0o1000;
+    }
+    unix_mode
+}
+
+/// Get the major number of a (Linux) device id
+#[allow(clippy::cast_possible_truncation)]
+const fn device_major(device: u64) -> u32 {
+    (((device >> 32) & 0xffff_f000) | ((device >> 8) & 0x0000_0fff)) as u32
+}
+
+/// Get the minor number of a (Linux) device id
+#[allow(clippy::cast_possible_truncation)]
+const fn device_minor(device: u64) -> u32 {
+    (((device >> 12) & 0xffff_ff00) | (device & 0x0000_00ff)) as u32
+}
+
+/// A writer which counts the written bytes
+struct CountingWriter<W> {
+    /// the inner writer
+    inner: W,
+    /// the number of written bytes
+    count: u64,
+}
+
+impl<W> CountingWriter<W> {
+    /// Create a new `CountingWriter`
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - the inner writer
+    const fn new(inner: W) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A user or group, given by name or numeric id
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Owner {
+    /// numeric id
+    Id(u32),
+    /// name
+    Name(String),
+}
+
+impl FromStr for Owner {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            bail!("empty user or group");
+        }
+        Ok(s.parse()
+            .map_or_else(|_| Self::Name(s.to_string()), Self::Id))
+    }
+}
+
+impl Owner {
+    /// Check if the owner matches the given id and name
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - the numeric id
+    /// * `name` - the name
+    fn matches(&self, id: Option<u32>, name: Option<&str>) -> bool {
+        match self {
+            Self::Id(i) => id == Some(*i),
+            Self::Name(n) => name == Some(n.as_str()),
+        }
+    }
+}
+
+/// Mapping of a user or group OLD to NEW, given as `OLD:NEW`
+#[derive(Clone, Debug)]
+struct OwnerMapping {
+    /// the user or group to map
+    from: Owner,
+    /// the user or group to map to
+    to: Owner,
+}
+
+impl FromStr for OwnerMapping {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((from, to)) = s.split_once(':') else {
+            bail!("invalid mapping {s:?}, expected OLD:NEW");
+        };
+        Ok(Self {
+            from: from.parse()?,
+            to: to.parse()?,
+        })
+    }
+}
+
+/// Parse the content of a mapping file
+///
+/// Each line is either `user OLD:NEW` or `group OLD:NEW`. Empty lines and lines starting with '#'
+/// are ignored.
+///
+/// # Arguments
+///
+/// * `content` - the content of the mapping file
+/// * `users` - the user mappings to add to
+/// * `groups` - the group mappings to add to
+fn parse_mapping_file(
+    content: &str,
+    users: &mut Vec<OwnerMapping>,
+    groups: &mut Vec<OwnerMapping>,
+) -> Result<()> {
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || {
+            format!(
+                "invalid line {}, expected \"user OLD:NEW\" or \"group OLD:NEW\"",
+                number + 1
+            )
+        };
+        let (kind, mapping) = line.split_once(char::is_whitespace).with_context(invalid)?;
+        let mapping: OwnerMapping = mapping.trim().parse().with_context(invalid)?;
+        match kind {
+            "user" => users.push(mapping),
+            "group" => groups.push(mapping),
+            _ => bail!(invalid()),
+        }
+    }
+    Ok(())
+}
+
+/// Mapping of users and groups applied to the restored nodes
+///
+/// The new owners are resolved once: A new numeric id removes the name, so that the numeric id is
+/// used when restoring. A new name is kept together with its id on this host, if it exists there,
+/// so that `--numeric-id` decides whether the name or the id is used.
+#[derive(Debug)]
+struct OwnerMap {
+    /// the user mappings, the first matching one is used
+    users: Vec<(Owner, Option<u32>, Option<String>)>,
+    /// the group mappings, the first matching one is used
+    groups: Vec<(Owner, Option<u32>, Option<String>)>,
+}
+
+impl OwnerMap {
+    /// Create a new `OwnerMap` and resolve the new owners
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - the user mappings
+    /// * `groups` - the group mappings
+    fn new(users: &[OwnerMapping], groups: &[OwnerMapping]) -> Self {
+        Self::with_lookup(users, groups, uid_from_name, gid_from_name)
+    }
+
+    /// Create a new `OwnerMap` and resolve the new owners using the given lookup functions
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - the user mappings
+    /// * `groups` - the group mappings
+    /// * `uid_lookup` - function to get the uid of a user name
+    /// * `gid_lookup` - function to get the gid of a group name
+    fn with_lookup(
+        users: &[OwnerMapping],
+        groups: &[OwnerMapping],
+        uid_lookup: fn(&str) -> Option<u32>,
+        gid_lookup: fn(&str) -> Option<u32>,
+    ) -> Self {
+        let resolve = |mappings: &[OwnerMapping], lookup: fn(&str) -> Option<u32>| -> Vec<_> {
+            mappings
+                .iter()
+                .map(|mapping| match &mapping.to {
+                    Owner::Id(id) => (mapping.from.clone(), Some(*id), None),
+                    Owner::Name(name) => {
+                        let id = lookup(name);
+                        if id.is_none() {
+                            warn!("{name} does not exist on this host, keeping numeric id.");
+                        }
+                        (mapping.from.clone(), id, Some(name.clone()))
+                    }
+                })
+                .collect()
+        };
+        Self {
+            users: resolve(users, uid_lookup),
+            groups: resolve(groups, gid_lookup),
+        }
+    }
+
+    /// Apply the mappings to the metadata of a node
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - the node to change
+    fn apply(&self, node: &mut Node) {
+        let meta = &mut node.meta;
+        if let Some((_, uid, user)) = self
+            .users
+            .iter()
+            .find(|(from, _, _)| from.matches(meta.uid, meta.user.as_deref()))
+        {
+            meta.uid = uid.or(meta.uid);
+            meta.user = user.clone();
+        }
+        if let Some((_, gid, group)) = self
+            .groups
+            .iter()
+            .find(|(from, _, _)| from.matches(meta.gid, meta.group.as_deref()))
+        {
+            meta.gid = gid.or(meta.gid);
+            meta.group = group.clone();
+        }
+    }
+}
+
+/// Get the uid of a user on this host
+#[cfg(not(windows))]
+fn uid_from_name(name: &str) -> Option<u32> {
+    nix::unistd::User::from_name(name)
+        .ok()
+        .flatten()
+        .map(|user| user.uid.as_raw())
+}
+
+/// Get the gid of a group on this host
+#[cfg(not(windows))]
+fn gid_from_name(name: &str) -> Option<u32> {
+    nix::unistd::Group::from_name(name)
+        .ok()
+        .flatten()
+        .map(|group| group.gid.as_raw())
+}
+
+/// Get the uid of a user on this host (not supported on windows)
+#[cfg(windows)]
+fn uid_from_name(_name: &str) -> Option<u32> {
+    None
+}
+
+/// Get the gid of a group on this host (not supported on windows)
+#[cfg(windows)]
+fn gid_from_name(_name: &str) -> Option<u32> {
+    None
+}
+
+/// Options to rewrite the paths of restored nodes
+///
+/// The paths are relative to the restored snapshot path. When restoring, the rewritten entries
+/// need to be sorted, so all entries are held in memory, see [`PathRewriteOptions::rewrite_all`].
+#[derive(clap::Parser, Clone, Debug, Default)]
+pub(crate) struct PathRewriteOptions {
+    /// Remove the given number of leading components from the paths. Entries with no remaining
+    /// path are skipped. Note: When restoring with rewritten paths, all entries are held in memory
+    #[clap(long, value_name = "N", default_value = "0")]
+    strip_components: usize,
+
+    /// Replace the leading path FROM by TO, applied after --strip-components (can be specified
+    /// multiple times, the first matching one is used)
+    #[clap(long, value_name = "FROM=TO")]
+    rewrite_prefix: Vec<PrefixRewrite>,
+}
+
+impl PathRewriteOptions {
+    /// Check if no path rewriting is requested
+    pub(crate) fn is_empty(&self) -> bool {
+        self.strip_components == 0 && self.rewrite_prefix.is_empty()
+    }
+
+    /// Rewrite a path
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path to rewrite
+    ///
+    /// # Returns
+    ///
+    /// The rewritten path or `None` if no path remains
+    pub(crate) fn rewrite(&self, path: &Path) -> Option<PathBuf> {
+        let path: PathBuf = path.components().skip(self.strip_components).collect();
+        let path = self
+            .rewrite_prefix
+            .iter()
+            .find_map(|prefix| Some(prefix.to.join(path.strip_prefix(&prefix.from).ok()?)))
+            .unwrap_or(path);
+        (!path.as_os_str().is_empty()).then_some(path)
+    }
+
+    /// Rewrite the paths of all nodes
+    ///
+    /// As the restore needs the nodes in tree order, the rewritten nodes are sorted by path.
+    /// Note: This collects all nodes, so the memory usage grows with the number of nodes.
+    ///
+    /// # Arguments
+    ///
+    /// * `nodes` - the nodes to rewrite
+    ///
+    /// # Errors
+    ///
+    /// If reading a node fails or two nodes get the same path
+    pub(crate) fn rewrite_all<T, E>(
+        &self,
+        nodes: impl Iterator<Item = std::result::Result<(PathBuf, T), E>>,
+    ) -> Result<Vec<(PathBuf, T)>>
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let mut nodes = nodes
+            .filter_map(|item| match item {
+                Ok((path, node)) => self.rewrite(&path).map(|path| Ok((path, node))),
+                Err(err) => Some(Err(err)),
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        nodes.sort_by(|(path1, _), (path2, _)| path1.cmp(path2));
+        if let Some(entries) = nodes
+            .windows(2)
+            .find(|entries| entries[0].0 == entries[1].0)
+        {
+            bail!("multiple entries are rewritten to {:?}!", entries[0].0);
+        }
+        Ok(nodes)
+    }
+}
+
+/// Replacement of a leading path, given as `FROM=TO`
+#[derive(Clone, Debug)]
+struct PrefixRewrite {
+    /// the leading path to replace
+    from: PathBuf,
+    /// the path to replace it with
+    to: PathBuf,
+}
+
+impl FromStr for PrefixRewrite {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((from, to)) = s.split_once('=') else {
+            bail!("invalid prefix rewrite {s:?}, expected FROM=TO");
+        };
+        let (from, to) = (PathBuf::from(from), PathBuf::from(to));
+        // only allow relative paths within the restore destination
+        for path in [&from, &to] {
+            if path
+                .components()
+                .any(|comp| !matches!(comp, Component::Normal(_)))
+            {
+                bail!("invalid prefix rewrite {s:?}, only relative paths without .. are allowed");
+            }
+        }
+        if from.as_os_str().is_empty() {
+            bail!("invalid prefix rewrite {s:?}, FROM must not be empty");
+        }
+        Ok(Self { from, to })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        device_major, device_minor, parse_mapping_file, tar_header, unix_mode, Owner, OwnerMap,
+        OwnerMapping, PathRewriteOptions, PrefixRewrite,
+    };
+    use rustic_core::repofile::{Node, NodeType};
+    use std::{convert::Infallible, path::PathBuf};
+    use tar::EntryType;
+
+    /// Create a (Linux) device id from its major and minor number
+    const fn makedev(major: u64, minor: u64) -> u64 {
+        ((major & 0xffff_f000) << 32)
+            | ((major & 0x0000_0fff) << 8)
+            | ((minor & 0xffff_ff00) << 12)
+            | (minor & 0x0000_00ff)
+    }
+
+    #[test]
+    fn device_numbers() {
+        for (major, minor) in [(0, 0), (8, 1), (259, 300_000), (0xffff_ffff, 0xffff_ffff)] {
+            let device = makedev(major, minor);
+            assert_eq!(u64::from(device_major(device)), major);
+            assert_eq!(u64::from(device_minor(device)), minor);
+        }
+    }
+
+    #[test]
+    fn convert_mode() {
+        assert_eq!(unix_mode(0o644), 0o644);
+        // dir bit of Go's os.FileMode
+        assert_eq!(unix_mode((1 << 31) | 0o755), 0o755);
+        assert_eq!(unix_mode((1 << 23) | 0o755), 0o4755);
+        assert_eq!(unix_mode((1 << 22) | 0o755), 0o2755);
+        assert_eq!(unix_mode((1 << 31) | (1 << 20) | 0o777), 0o1777);
+    }
+
+    #[test]
+    fn create_tar_header() {
+        let node = |node_type, mode| {
+            let mut node = Node {
+                name: "test".to_string(),
+                node_type,
+                ..Default::default()
+            };
+            node.meta.mode = mode;
+            node.meta.size = 42;
+            node
+        };
+
+        let header = tar_header(&node(NodeType::Dir, None)).unwrap();
+        assert_eq!(header.entry_type(), EntryType::Directory);
+        assert_eq!(header.mode().unwrap(), 0o755);
+        assert_eq!(header.size().unwrap(), 0);
+
+        let header = tar_header(&node(NodeType::File, None)).unwrap();
+        assert_eq!(header.entry_type(), EntryType::Regular);
+        assert_eq!(header.mode().unwrap(), 0o644);
+        assert_eq!(header.size().unwrap(), 42);
+
+        let header = tar_header(&node(NodeType::File, Some((1 << 23) | 0o755))).unwrap();
+        assert_eq!(header.mode().unwrap(), 0o4755);
+
+        let device = makedev(8, 1);
+        let header = tar_header(&node(NodeType::Dev { device }, Some(0o660))).unwrap();
+        assert_eq!(header.entry_type(), EntryType::Block);
+        assert_eq!(header.device_major().unwrap(), Some(8));
+        assert_eq!(header.device_minor().unwrap(), Some(1));
+        assert_eq!(header.size().unwrap(), 0);
+
+        assert!(tar_header(&node(NodeType::Socket, None)).is_err());
+    }
+
+    #[test]
+    fn parse_owner() {
+        assert_eq!("1000".parse::<Owner>().unwrap(), Owner::Id(1000));
+        assert_eq!(
+            "alice".parse::<Owner>().unwrap(),
+            Owner::Name("alice".to_string())
+        );
+        assert!("".parse::<Owner>().is_err());
+
+        let mapping: OwnerMapping = "alice:1001".parse().unwrap();
+        assert_eq!(mapping.from, Owner::Name("alice".to_string()));
+        assert_eq!(mapping.to, Owner::Id(1001));
+        assert!("alice".parse::<OwnerMapping>().is_err());
+        assert!("alice:".parse::<OwnerMapping>().is_err());
+        assert!(":1001".parse::<OwnerMapping>().is_err());
+    }
+
+    #[test]
+    fn parse_mappings() {
+        let content = "# comment\n\nuser alice:bob\n  group 100:staff  \nuser\t0:1000\n";
+        let (mut users, mut groups) = (Vec::new(), Vec::new());
+        parse_mapping_file(content, &mut users, &mut groups).unwrap();
+        let users: Vec<_> = users.into_iter().map(|m| (m.from, m.to)).collect();
+        let groups: Vec<_> = groups.into_iter().map(|m| (m.from, m.to)).collect();
+        assert_eq!(
+            users,
+            vec![
+                (
+                    Owner::Name("alice".to_string()),
+                    Owner::Name("bob".to_string())
+                ),
+                (Owner::Id(0), Owner::Id(1000)),
+            ]
+        );
+        assert_eq!(
+            groups,
+            vec![(Owner::Id(100), Owner::Name("staff".to_string()))]
+        );
+
+        for content in ["user alice", "owner alice:bob", "user", "user :bob"] {
+            let (mut users, mut groups) = (Vec::new(), Vec::new());
+            assert!(parse_mapping_file(content, &mut users, &mut groups).is_err());
+        }
+    }
+
+    #[test]
+    fn apply_owner_map() {
+        let lookup = |name: &str| match name {
+            "bob" => Some(1001),
+            "staff" => Some(50),
+            _ => None,
+        };
+        let users: Vec<OwnerMapping> = ["alice:bob", "0:1000", "carol:unknown"]
+            .iter()
+            .map(|m| m.parse().unwrap())
+            .collect();
+        let groups: Vec<OwnerMapping> = vec!["100:staff".parse().unwrap()];
+        let map = OwnerMap::with_lookup(&users, &groups, lookup, lookup);
+
+        let node = |uid, user: &str, gid, group: &str| {
+            let mut node = Node {
+                name: "test".to_string(),
+                node_type: NodeType::File,
+                ..Default::default()
+            };
+            node.meta.uid = Some(uid);
+            node.meta.user = Some(user.to_string());
+            node.meta.gid = Some(gid);
+            node.meta.group = Some(group.to_string());
+            node
+        };
+        let owners = |node: &Node| {
+            let meta = &node.meta;
+            (meta.uid, meta.user.clone(), meta.gid, meta.group.clone())
+        };
+
+        // a new name is resolved to its id on this host
+        let mut n = node(500, "alice", 100, "users");
+        map.apply(&mut n);
+        assert_eq!(
+            owners(&n),
+            (
+                Some(1001),
+                Some("bob".to_string()),
+                Some(50),
+                Some("staff".to_string())
+            )
+        );
+
+        // a new numeric id removes the name
+        let mut n = node(0, "root", 0, "root");
+        map.apply(&mut n);
+        assert_eq!(
+            owners(&n),
+            (Some(1000), None, Some(0), Some("root".to_string()))
+        );
+
+        // a new name which doesn't exist on this host keeps the old id
+        let mut n = node(600, "carol", 0, "root");
+        map.apply(&mut n);
+        assert_eq!(
+            owners(&n),
+            (
+                Some(600),
+                Some("unknown".to_string()),
+                Some(0),
+                Some("root".to_string())
+            )
+        );
+
+        // nodes not matching any mapping are unchanged
+        let mut n = node(700, "dave", 700, "dave");
+        map.apply(&mut n);
+        assert_eq!(
+            owners(&n),
+            (
+                Some(700),
+                Some("dave".to_string()),
+                Some(700),
+                Some("dave".to_string())
+            )
+        );
+    }
+
+    fn rewrite_opts(strip_components: usize, rewrite_prefix: &[&str]) -> PathRewriteOptions {
+        PathRewriteOptions {
+            strip_components,
+            rewrite_prefix: rewrite_prefix.iter().map(|s| s.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn parse_prefix_rewrite() {
+        let rewrite: PrefixRewrite = "a/b=c".parse().unwrap();
+        assert_eq!(rewrite.from, PathBuf::from("a/b"));
+        assert_eq!(rewrite.to, PathBuf::from("c"));
+        let rewrite: PrefixRewrite = "a=".parse().unwrap();
+        assert_eq!(rewrite.to, PathBuf::new());
+
+        for s in ["a", "=b", "/a=b", "a=/b", "../a=b", "a=b/../c", "./a=b"] {
+            assert!(s.parse::<PrefixRewrite>().is_err(), "{s} should be invalid");
+        }
+    }
+
+    #[test]
+    fn rewrite_path() {
+        let opts = rewrite_opts(0, &[]);
+        assert!(opts.is_empty());
+        assert_eq!(opts.rewrite("a/b".as_ref()), Some(PathBuf::from("a/b")));
+
+        let opts = rewrite_opts(1, &[]);
+        assert_eq!(opts.rewrite("a/b/c".as_ref()), Some(PathBuf::from("b/c")));
+        assert_eq!(opts.rewrite("a".as_ref()), None);
+
+        let opts = rewrite_opts(1, &["b=x/y", "b/c=z", "d="]);
+        assert_eq!(opts.rewrite("a/b/c".as_ref()), Some(PathBuf::from("x/y/c")));
+        assert_eq!(opts.rewrite("a/bc".as_ref()), Some(PathBuf::from("bc")));
+        assert_eq!(opts.rewrite("a/d/e".as_ref()), Some(PathBuf::from("e")));
+        assert_eq!(opts.rewrite("a/d".as_ref()), None);
+    }
+
+    #[test]
+    fn rewrite_all_paths() {
+        let nodes = |paths: &[&'static str]| {
+            paths
+                .iter()
+                .map(|path| Ok::<_, Infallible>((PathBuf::from(path), *path)))
+                .collect::<Vec<_>>()
+                .into_iter()
+        };
+
+        let opts = rewrite_opts(1, &["b=z"]);
+        let rewritten = opts
+            .rewrite_all(nodes(&["a", "a/b", "a/b/c", "a/a"]))
+            .unwrap();
+        assert_eq!(
+            rewritten,
+            vec![
+                (PathBuf::from("a"), "a/a"),
+                (PathBuf::from("z"), "a/b"),
+                (PathBuf::from("z/c"), "a/b/c"),
+            ]
+        );
+
+        // both a/b and c/b are rewritten to b
+        let opts = rewrite_opts(1, &[]);
+        assert!(opts.rewrite_all(nodes(&["a/b", "c/b"])).is_err());
+    }
 }
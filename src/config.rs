@@ -4,6 +4,7 @@
 //! application's configuration file and/or command-line options
 //! for specifying it.
 
+pub(crate) mod hooks;
 pub(crate) mod progress_options;
 
 use std::{collections::HashMap, path::PathBuf};
@@ -21,7 +22,7 @@ use rustic_core::RepositoryOptions;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    commands::{backup::BackupCmd, copy::Targets, forget::ForgetOptions},
+    commands::{backup::BackupCmd, copy::Targets, daemon::DaemonOptions, forget::ForgetOptions},
     config::progress_options::ProgressOptions,
     filtering::SnapshotFilter,
 };
@@ -58,6 +59,10 @@ pub struct RusticConfig {
     /// Forget options
     #[clap(skip)]
     pub forget: ForgetOptions,
+
+    /// Daemon options
+    #[clap(skip)]
+    pub daemon: DaemonOptions,
 }
 
 impl RusticConfig {
@@ -0,0 +1,122 @@
+//! Hooks Config
+//!
+//! Commands which are run before, after or on failure of an action.
+
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use log::{debug, warn};
+use merge::Merge;
+use serde::{Deserialize, Serialize};
+
+/// Hooks Config
+///
+/// Each command is split into program and arguments like a shell would do, but it is not run within a shell.
+#[derive(Default, Debug, Parser, Clone, Deserialize, Serialize, Merge)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Hooks {
+    /// Command to run before the backup of a source (can be specified multiple times).
+    /// If a command fails, the backup of this source is aborted.
+    #[clap(long, value_name = "COMMAND")]
+    #[merge(strategy = merge::vec::overwrite_empty)]
+    pub run_before: Vec<String>,
+
+    /// Command to run after a successful backup of a source (can be specified multiple times)
+    #[clap(long, value_name = "COMMAND")]
+    #[merge(strategy = merge::vec::overwrite_empty)]
+    pub run_after: Vec<String>,
+
+    /// Command to run after a failed backup of a source (can be specified multiple times)
+    #[clap(long, value_name = "COMMAND")]
+    #[merge(strategy = merge::vec::overwrite_empty)]
+    pub run_on_failure: Vec<String>,
+
+    /// Command to always run after the backup of a source (can be specified multiple times)
+    #[clap(long, value_name = "COMMAND")]
+    #[merge(strategy = merge::vec::overwrite_empty)]
+    pub run_finally: Vec<String>,
+}
+
+impl Hooks {
+    /// Run the `run-before` commands
+    ///
+    /// # Arguments
+    ///
+    /// * `env` - environment variables to set for the commands
+    ///
+    /// # Errors
+    ///
+    /// If a command could not be started or did not exit successfully
+    pub fn run_before(&self, env: &[(&str, String)]) -> Result<()> {
+        Self::run_all(&self.run_before, "run-before", env)
+    }
+
+    /// Run the `run-after` commands
+    ///
+    /// # Arguments
+    ///
+    /// * `env` - environment variables to set for the commands
+    ///
+    /// # Errors
+    ///
+    /// If a command could not be started or did not exit successfully
+    pub fn run_after(&self, env: &[(&str, String)]) -> Result<()> {
+        Self::run_all(&self.run_after, "run-after", env)
+    }
+
+    /// Run the `run-on-failure` commands
+    ///
+    /// Errors of the commands are only logged as the action already failed.
+    ///
+    /// # Arguments
+    ///
+    /// * `env` - environment variables to set for the commands
+    pub fn run_on_failure(&self, env: &[(&str, String)]) {
+        if let Err(err) = Self::run_all(&self.run_on_failure, "run-on-failure", env) {
+            warn!("{err:#}");
+        }
+    }
+
+    /// Run the `run-finally` commands
+    ///
+    /// # Arguments
+    ///
+    /// * `env` - environment variables to set for the commands
+    ///
+    /// # Errors
+    ///
+    /// If a command could not be started or did not exit successfully
+    pub fn run_finally(&self, env: &[(&str, String)]) -> Result<()> {
+        Self::run_all(&self.run_finally, "run-finally", env)
+    }
+
+    /// Run the given commands one after another, stopping at the first failing command
+    ///
+    /// # Arguments
+    ///
+    /// * `commands` - the commands to run
+    /// * `context` - name of the hook, used for messages
+    /// * `env` - environment variables to set for the commands
+    fn run_all(commands: &[String], context: &str, env: &[(&str, String)]) -> Result<()> {
+        for command in commands {
+            let args = shell_words::split(command)
+                .with_context(|| format!("error parsing {context} command {command:?}"))?;
+            let Some((program, args)) = args.split_first() else {
+                bail!("{context} command is empty!");
+            };
+            debug!("calling {context} command {command:?}");
+            // don't pass stdin, it may be redirected by a stdin-command of another source
+            let status = Command::new(program)
+                .args(args)
+                .envs(env.iter().cloned())
+                .stdin(Stdio::null())
+                .status()
+                .with_context(|| format!("error calling {context} command {command:?}"))?;
+            if !status.success() {
+                bail!("{context} command {command:?} was not successful: {status}");
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,10 +1,22 @@
 //! Progress Bar Config
 
-use std::{borrow::Cow, fmt::Write, time::Duration};
+#[cfg(not(windows))]
+use std::fs::OpenOptions;
+use std::{
+    borrow::Cow,
+    fmt::Write,
+    io::{self, Write as _},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock, PoisonError,
+    },
+    time::{Duration, Instant},
+};
 
-use indicatif::{HumanDuration, ProgressBar, ProgressState, ProgressStyle};
+use indicatif::{HumanDuration, MultiProgress, ProgressBar, ProgressState, ProgressStyle};
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use log::warn;
 use merge::Merge;
 
 use serde::{Deserialize, Serialize};
@@ -32,6 +44,38 @@ pub struct ProgressOptions {
     )]
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub progress_interval: Option<humantime::Duration>,
+
+    /// Format of the progress output [default: text]
+    #[clap(
+        long,
+        global = true,
+        env = "RUSTIC_PROGRESS_FORMAT",
+        value_name = "FORMAT",
+        conflicts_with = "no_progress"
+    )]
+    pub progress_format: Option<ProgressFormat>,
+
+    /// File descriptor to write json progress events to [default: 2 (stderr)].
+    /// Note: Log messages are also written to stderr; use another fd or --log-file to separate them.
+    /// On windows, only 1 (stdout) and 2 (stderr) are supported
+    #[clap(
+        long,
+        global = true,
+        env = "RUSTIC_PROGRESS_FD",
+        value_name = "FD",
+        conflicts_with = "no_progress"
+    )]
+    pub progress_fd: Option<i32>,
+}
+
+/// Format of the progress output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProgressFormat {
+    /// Progress bars for the terminal
+    Text,
+    /// One json object per line for each progress event
+    Json,
 }
 
 impl ProgressOptions {
@@ -46,10 +90,53 @@ impl ProgressOptions {
 
     /// Create a hidden progress bar
     pub fn no_progress() -> RusticProgress {
-        RusticProgress(ProgressBar::hidden())
+        RusticProgress::Bar(ProgressBar::hidden())
+    }
+
+    /// Add a progress bar to the terminal output
+    ///
+    /// All bars are drawn by a single [`MultiProgress`], so that bars which are shown at the same
+    /// time, e.g. when backing up sources in parallel, don't overwrite each other.
+    ///
+    /// # Arguments
+    ///
+    /// * `p` - the progress bar to add
+    fn add_bar(&self, p: ProgressBar) -> RusticProgress {
+        static MULTI_PROGRESS: OnceLock<MultiProgress> = OnceLock::new();
+        let p = MULTI_PROGRESS.get_or_init(MultiProgress::new).add(p);
+        p.enable_steady_tick(self.progress_interval());
+        RusticProgress::Bar(p)
+    }
+
+    /// Create a json progress if json progress output is enabled
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - the phase of the progress
+    /// * `unit` - the unit of the progress, if it is counted
+    fn json_progress(
+        &self,
+        prefix: impl Into<Cow<'static, str>>,
+        unit: Option<ProgressUnit>,
+    ) -> Option<RusticProgress> {
+        (self.progress_format == Some(ProgressFormat::Json)).then(|| {
+            let interval = self
+                .progress_interval
+                .map_or(constants::DEFAULT_JSON_INTERVAL, |i| *i);
+            let p = JsonProgress::new(prefix.into(), unit, interval, self.progress_fd);
+            p.emit(EventKind::Start);
+            RusticProgress::Json(Arc::new(p))
+        })
     }
 }
 
+mod constants {
+    use std::time::Duration;
+
+    /// Default interval between two json progress events
+    pub(super) const DEFAULT_JSON_INTERVAL: Duration = Duration::from_secs(1);
+}
+
 impl ProgressBars for ProgressOptions {
     type P = RusticProgress;
 
@@ -57,28 +144,34 @@ impl ProgressBars for ProgressOptions {
         if self.no_progress {
             return Self::no_progress();
         }
+        let prefix = prefix.into();
+        if let Some(p) = self.json_progress(prefix.clone(), None) {
+            return p;
+        }
         let p = ProgressBar::new(0).with_style(
             ProgressStyle::default_bar()
                 .template("[{elapsed_precise}] {prefix:30} {spinner}")
                 .unwrap(),
         );
         p.set_prefix(prefix);
-        p.enable_steady_tick(self.progress_interval());
-        RusticProgress(p)
+        self.add_bar(p)
     }
 
     fn progress_counter(&self, prefix: impl Into<Cow<'static, str>>) -> RusticProgress {
         if self.no_progress {
             return Self::no_progress();
         }
+        let prefix = prefix.into();
+        if let Some(p) = self.json_progress(prefix.clone(), Some(ProgressUnit::Items)) {
+            return p;
+        }
         let p = ProgressBar::new(0).with_style(
             ProgressStyle::default_bar()
                 .template("[{elapsed_precise}] {prefix:30} {bar:40.cyan/blue} {pos:>10}/{len:10}")
                 .unwrap(),
         );
         p.set_prefix(prefix);
-        p.enable_steady_tick(self.progress_interval());
-        RusticProgress(p)
+        self.add_bar(p)
     }
 
     fn progress_hidden(&self) -> RusticProgress {
@@ -89,6 +182,10 @@ impl ProgressBars for ProgressOptions {
         if self.no_progress {
             return Self::no_progress();
         }
+        let prefix = prefix.into();
+        if let Some(p) = self.json_progress(prefix.clone(), Some(ProgressUnit::Bytes)) {
+            return p;
+        }
         let p = ProgressBar::new(0).with_style(
             ProgressStyle::default_bar()
             .with_key("my_eta", |s: &ProgressState, w: &mut dyn Write| 
@@ -100,33 +197,257 @@ impl ProgressBars for ProgressOptions {
             .unwrap()
             );
         p.set_prefix(prefix);
-        p.enable_steady_tick(self.progress_interval());
-        RusticProgress(p)
+        self.add_bar(p)
     }
 }
 
-/// A default progress bar
+/// A default progress bar or a json progress
 #[derive(Debug, Clone)]
-pub struct RusticProgress(ProgressBar);
+pub enum RusticProgress {
+    /// A progress bar for the terminal
+    Bar(ProgressBar),
+    /// A progress which writes json events
+    Json(Arc<JsonProgress>),
+}
 
 impl Progress for RusticProgress {
     fn is_hidden(&self) -> bool {
-        self.0.is_hidden()
+        match self {
+            Self::Bar(p) => p.is_hidden(),
+            Self::Json(_) => false,
+        }
     }
 
     fn set_length(&self, len: u64) {
-        self.0.set_length(len);
+        match self {
+            Self::Bar(p) => p.set_length(len),
+            Self::Json(p) => p.len.store(len, Ordering::Relaxed),
+        }
     }
 
     fn set_title(&self, title: &'static str) {
-        self.0.set_prefix(title);
+        match self {
+            Self::Bar(p) => p.set_prefix(title),
+            Self::Json(p) => {
+                *p.phase.lock().unwrap_or_else(PoisonError::into_inner) = title.into();
+                p.emit(EventKind::Start);
+            }
+        }
     }
 
     fn inc(&self, inc: u64) {
-        self.0.inc(inc);
+        match self {
+            Self::Bar(p) => p.inc(inc),
+            Self::Json(p) => {
+                _ = p.pos.fetch_add(inc, Ordering::Relaxed);
+                p.emit(EventKind::Progress);
+            }
+        }
     }
 
     fn finish(&self) {
-        self.0.finish_with_message("done");
+        match self {
+            Self::Bar(p) => p.finish_with_message("done"),
+            Self::Json(p) => p.emit(EventKind::Finish),
+        }
     }
 }
+
+/// The unit of a counted progress
+#[derive(Debug, Clone, Copy)]
+enum ProgressUnit {
+    /// progress counts bytes
+    Bytes,
+    /// progress counts items, e.g. files, snapshots or packs
+    Items,
+}
+
+/// Kind of a json progress event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum EventKind {
+    /// a phase has started
+    Start,
+    /// the progress of a phase
+    Progress,
+    /// a phase is finished
+    Finish,
+}
+
+/// A json progress event
+///
+/// Note: The currently processed file is not contained, as the progress API of `rustic_core`
+/// doesn't pass it to the progress.
+#[derive(Debug, Serialize)]
+struct ProgressEvent<'a> {
+    /// the kind of the event
+    event: EventKind,
+    /// the phase, e.g. "backing up..."
+    phase: &'a str,
+    /// processed bytes, if the progress counts bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_done: Option<u64>,
+    /// total bytes, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_total: Option<u64>,
+    /// processed items, if the progress counts items
+    #[serde(skip_serializing_if = "Option::is_none")]
+    items_done: Option<u64>,
+    /// total items, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    items_total: Option<u64>,
+    /// elapsed time in seconds
+    elapsed: f64,
+    /// estimated remaining time in seconds, if it can be estimated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    eta: Option<f64>,
+}
+
+/// A progress which writes one json object per line for each event
+#[derive(Debug)]
+pub struct JsonProgress {
+    /// the current phase
+    phase: Mutex<Cow<'static, str>>,
+    /// the unit of the progress, `None` for spinners
+    unit: Option<ProgressUnit>,
+    /// the current position
+    pos: AtomicU64,
+    /// the total length, 0 if unknown
+    len: AtomicU64,
+    /// the start time of the progress
+    start: Instant,
+    /// minimum interval between two progress events
+    interval: Duration,
+    /// time of the last progress event in nanoseconds since `start`
+    last_emit: AtomicU64,
+    /// file descriptor to write to
+    fd: Option<i32>,
+}
+
+impl JsonProgress {
+    /// Create a new json progress
+    fn new(
+        phase: Cow<'static, str>,
+        unit: Option<ProgressUnit>,
+        interval: Duration,
+        fd: Option<i32>,
+    ) -> Self {
+        let start = Instant::now();
+        Self {
+            phase: Mutex::new(phase),
+            unit,
+            pos: AtomicU64::new(0),
+            len: AtomicU64::new(0),
+            start,
+            interval,
+            last_emit: AtomicU64::new(0),
+            fd,
+        }
+    }
+
+    /// Write an event. Progress events are only written if the interval since the last event has passed.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - the kind of event to write
+    fn emit(&self, event: EventKind) {
+        let elapsed = self.start.elapsed();
+        let elapsed_nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        if event == EventKind::Progress {
+            // this is called for each increment, so check the interval without locking
+            let last_emit = self.last_emit.load(Ordering::Relaxed);
+            let interval = u64::try_from(self.interval.as_nanos()).unwrap_or(u64::MAX);
+            if elapsed_nanos.saturating_sub(last_emit) < interval
+                || self
+                    .last_emit
+                    .compare_exchange(
+                        last_emit,
+                        elapsed_nanos,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    )
+                    .is_err()
+            {
+                // the interval has not passed or another thread emits this event
+                return;
+            }
+        } else {
+            self.last_emit.store(elapsed_nanos, Ordering::Relaxed);
+        }
+
+        let pos = self.pos.load(Ordering::Relaxed);
+        let len = self.len.load(Ordering::Relaxed);
+        let eta = len
+            .checked_sub(pos)
+            .filter(|_| pos != 0)
+            .map(|remaining| elapsed.as_secs_f64() * remaining as f64 / pos as f64);
+        let (bytes, items) = match self.unit {
+            Some(ProgressUnit::Bytes) => (Some((pos, len)), None),
+            Some(ProgressUnit::Items) => (None, Some((pos, len))),
+            None => (None, None),
+        };
+        let total = |(_, len): (u64, u64)| (len != 0).then_some(len);
+
+        let phase = self
+            .phase
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let progress_event = ProgressEvent {
+            event,
+            phase: &phase,
+            bytes_done: bytes.map(|(pos, _)| pos),
+            bytes_total: bytes.and_then(total),
+            items_done: items.map(|(pos, _)| pos),
+            items_total: items.and_then(total),
+            elapsed: elapsed.as_secs_f64(),
+            eta,
+        };
+
+        let writer = json_writer(self.fd);
+        let mut writer = writer.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(err) = serde_json::to_writer(&mut *writer, &progress_event)
+            .map_err(io::Error::from)
+            .and_then(|()| writeln!(writer))
+            .and_then(|()| writer.flush())
+        {
+            warn!("error writing progress: {err}");
+        }
+    }
+}
+
+/// The writer for json progress events
+static JSON_WRITER: OnceLock<Mutex<Box<dyn io::Write + Send>>> = OnceLock::new();
+
+/// Get the writer for json progress events, opening it on first use
+///
+/// # Arguments
+///
+/// * `fd` - file descriptor to write to; stderr if not given
+///
+/// # Note
+///
+/// Other file descriptors than stdout and stderr are opened via `/dev/fd`, which doesn't exist on
+/// windows. There, stderr is used instead.
+fn json_writer(fd: Option<i32>) -> &'static Mutex<Box<dyn io::Write + Send>> {
+    JSON_WRITER.get_or_init(|| {
+        let writer: Box<dyn io::Write + Send> = match fd {
+            None | Some(2) => Box::new(io::stderr()),
+            Some(1) => Box::new(io::stdout()),
+            #[cfg(not(windows))]
+            Some(fd) => match OpenOptions::new().write(true).open(format!("/dev/fd/{fd}")) {
+                Ok(file) => Box::new(file),
+                Err(err) => {
+                    warn!("cannot open file descriptor {fd} for progress output, using stderr: {err}");
+                    Box::new(io::stderr())
+                }
+            },
+            #[cfg(windows)]
+            Some(fd) => {
+                warn!("file descriptor {fd} for progress output is not supported on windows, using stderr");
+                Box::new(io::stderr())
+            }
+        };
+        Mutex::new(writer)
+    })
+}
@@ -188,3 +188,112 @@ fn test_backup_and_restore_passes() -> TestResult<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_backup_print_changes_passes() -> TestResult<()> {
+    let temp_dir = setup()?;
+    let source_dir = temp_dir.path().join("source");
+    std::fs::create_dir(&source_dir)?;
+    std::fs::write(source_dir.join("kept.txt"), "kept content")?;
+    std::fs::write(source_dir.join("modified.txt"), "old content")?;
+
+    let backup_changes = || -> TestResult<Vec<(String, String)>> {
+        let mut runner = rustic_runner(&temp_dir);
+        let mut cmd = runner
+            .args(["backup", "--print-changes", "--json"])
+            .arg(&source_dir)
+            .run();
+
+        let mut output = String::new();
+        cmd.stdout().read_to_string(&mut output)?;
+        cmd.wait()?.expect_success();
+
+        let result: serde_json::Value = serde_json::from_str(&output)?;
+        let changes = result["changes"]
+            .as_array()
+            .expect("changes should be an array")
+            .iter()
+            .map(|change| {
+                let tpe = change["type"].as_str().unwrap().to_string();
+                let path = change["path"].as_str().unwrap();
+                let name = path.rsplit(['/', '\\']).next().unwrap().to_string();
+                (tpe, name)
+            })
+            .collect();
+        Ok(changes)
+    };
+
+    // Run `backup` for the first time; all files are added
+    let changes = backup_changes()?;
+    assert!(changes.contains(&("added".to_string(), "kept.txt".to_string())));
+    assert!(changes.contains(&("added".to_string(), "modified.txt".to_string())));
+
+    // Run `backup` a second time after modifying a file
+    std::fs::write(source_dir.join("modified.txt"), "new and longer content")?;
+    let changes = backup_changes()?;
+    assert_eq!(
+        changes,
+        vec![("modified".to_string(), "modified.txt".to_string())]
+    );
+
+    Ok(())
+}
+
+#[cfg(not(windows))]
+#[test]
+fn test_restore_to_tar_archive_passes() -> TestResult<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = setup()?;
+    let source_dir = temp_dir.path().join("source");
+    std::fs::create_dir_all(source_dir.join("dir"))?;
+    std::fs::write(source_dir.join("dir").join("file.txt"), "content")?;
+    std::fs::set_permissions(
+        source_dir.join("dir"),
+        std::fs::Permissions::from_mode(0o755),
+    )?;
+    let setuid_file = source_dir.join("setuid");
+    std::fs::write(&setuid_file, "")?;
+    std::fs::set_permissions(&setuid_file, std::fs::Permissions::from_mode(0o4755))?;
+    let archive = temp_dir.path().join("archive.tar");
+
+    {
+        // Run `backup`
+        let mut runner = rustic_runner(&temp_dir);
+        let cmd = runner.arg("backup").arg(&source_dir).run();
+        cmd.wait()?.expect_success();
+    }
+    {
+        // Run `restore --archive tar`
+        let mut runner = rustic_runner(&temp_dir);
+        let cmd = runner
+            .args(["restore", "--archive", "tar"])
+            .arg(format!("latest:{}", source_dir.display()))
+            .arg(&archive)
+            .run();
+        cmd.wait()?.expect_success();
+    }
+
+    // list the archive using `tar`
+    let output = std::process::Command::new("tar")
+        .arg("-tvf")
+        .arg(&archive)
+        .output()?;
+    assert!(output.status.success());
+    let listing = String::from_utf8(output.stdout)?;
+
+    let entries: Vec<_> = listing
+        .lines()
+        .map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            (fields[0], fields[fields.len() - 1])
+        })
+        .collect();
+    assert!(entries.contains(&("drwxr-xr-x", "dir")) || entries.contains(&("drwxr-xr-x", "dir/")));
+    assert!(entries
+        .iter()
+        .any(|(mode, path)| mode.starts_with('-') && *path == "dir/file.txt"));
+    assert!(entries.contains(&("-rwsr-xr-x", "setuid")));
+
+    Ok(())
+}